@@ -0,0 +1,475 @@
+//! Agent holding the unlocked identities, to decrypt without unlocking them every time.
+//!
+//! The clients send the stanzas of an age header over a Unix socket, one JSON request per
+//! connection, and the agent answers with the unwrapped file key.
+
+use std::{
+    fs,
+    io::{self, BufRead, BufReader, ErrorKind, Write, stdout},
+    os::unix::{
+        fs::{FileTypeExt, MetadataExt, PermissionsExt},
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    sync::mpsc::{self, RecvTimeoutError, Sender},
+    thread,
+    time::{Duration, Instant},
+};
+
+use age::{DecryptError, Identity, secrecy::ExposeSecret};
+use age_core::format::{FILE_KEY_BYTES, FileKey, Stanza};
+use base64::{Engine, prelude::BASE64_STANDARD};
+use color_eyre::{Section, owo_colors::OwoColorize};
+use eyre::{OptionExt, WrapErr, bail, eyre};
+use jiff::{Span, SpanRelativeTo};
+use rustix::{mm::MlockAllFlags, process::DumpableBehavior};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error, info, warn};
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::{config::Config, identity, temp};
+
+/// Time a client has to send a request.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Time the agent has to answer a request.
+const AGENT_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Request {
+    stanzas: Vec<WireStanza>,
+}
+
+/// Stanza of an age header, with the body in base64.
+#[derive(Debug, Serialize, Deserialize)]
+struct WireStanza {
+    tag: String,
+    args: Vec<String>,
+    body: String,
+}
+
+impl From<&Stanza> for WireStanza {
+    fn from(value: &Stanza) -> Self {
+        Self {
+            tag: value.tag.clone(),
+            args: value.args.clone(),
+            body: BASE64_STANDARD.encode(&value.body),
+        }
+    }
+}
+
+impl TryFrom<WireStanza> for Stanza {
+    type Error = base64::DecodeError;
+
+    fn try_from(value: WireStanza) -> Result<Self, Self::Error> {
+        Ok(Self {
+            tag: value.tag,
+            args: value.args,
+            body: BASE64_STANDARD.decode(value.body)?,
+        })
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Response {
+    /// File key in base64
+    FileKey(String),
+    NoMatch,
+    Error(String),
+}
+
+/// Returns the identities to decrypt with, the agent if configured and running.
+pub(crate) fn identities(config: &Config) -> eyre::Result<Vec<Box<dyn Identity>>> {
+    if let Some(sock) = &config.agent.sock {
+        match UnixStream::connect(sock) {
+            Ok(_) => {
+                debug!(sock = %sock.display(), "using the agent");
+
+                return Ok(vec![Box::new(AgentIdentity { sock: sock.clone() })]);
+            }
+            Err(err) => {
+                warn!(
+                    sock = %sock.display(),
+                    error = %err,
+                    "couldn't connect to the agent, using the key files"
+                );
+            }
+        }
+    }
+
+    config.identities()
+}
+
+/// Identity unwrapping the file keys through the agent.
+struct AgentIdentity {
+    sock: PathBuf,
+}
+
+impl AgentIdentity {
+    fn request(&self, stanzas: &[Stanza]) -> io::Result<Response> {
+        let mut stream = UnixStream::connect(&self.sock)?;
+        stream.set_read_timeout(Some(AGENT_TIMEOUT))?;
+        stream.set_write_timeout(Some(AGENT_TIMEOUT))?;
+
+        let request = Request {
+            stanzas: stanzas.iter().map(WireStanza::from).collect(),
+        };
+
+        serde_json::to_writer(&mut stream, &request)?;
+        stream.write_all(b"\n")?;
+        stream.flush()?;
+
+        let mut line = Zeroizing::new(String::new());
+        BufReader::new(stream).read_line(&mut line)?;
+
+        serde_json::from_str(&line).map_err(io::Error::from)
+    }
+}
+
+impl Identity for AgentIdentity {
+    fn unwrap_stanza(&self, stanza: &Stanza) -> Option<Result<FileKey, DecryptError>> {
+        self.unwrap_stanzas(std::slice::from_ref(stanza))
+    }
+
+    fn unwrap_stanzas(&self, stanzas: &[Stanza]) -> Option<Result<FileKey, DecryptError>> {
+        let response = match self.request(stanzas) {
+            Ok(response) => response,
+            Err(err) => return Some(Err(DecryptError::Io(err))),
+        };
+
+        match response {
+            Response::FileKey(encoded) => Some(decode_file_key(&Zeroizing::new(encoded))),
+            Response::NoMatch => None,
+            Response::Error(msg) => {
+                error!(error = msg, "the agent couldn't unwrap the file key");
+
+                Some(Err(DecryptError::KeyDecryptionFailed))
+            }
+        }
+    }
+}
+
+fn decode_file_key(encoded: &str) -> Result<FileKey, DecryptError> {
+    let decoded = Zeroizing::new(
+        BASE64_STANDARD
+            .decode(encoded)
+            .map_err(|_| DecryptError::KeyDecryptionFailed)?,
+    );
+
+    FileKey::try_init_with_mut(|file_key| {
+        if decoded.len() != FILE_KEY_BYTES {
+            return Err(DecryptError::KeyDecryptionFailed);
+        }
+
+        file_key.copy_from_slice(&decoded);
+
+        Ok(())
+    })
+}
+
+/// Runs the agent until it's idle for the timeout.
+pub fn run(sock: Option<&Path>, timeout: Option<Span>) -> eyre::Result<()> {
+    let config = crate::config();
+
+    let timeout = timeout
+        .unwrap_or(config.agent.timeout)
+        .to_duration(SpanRelativeTo::days_are_24_hours())
+        .wrap_err("invalid agent timeout")?
+        .unsigned_abs();
+
+    let sock = match sock.or(config.agent.sock.as_deref()) {
+        Some(sock) => sock.to_path_buf(),
+        None => temp::runtime_dir()
+            .ok_or_eyre("the runtime directory is not set")
+            .note(format!("pass the socket path with {}", "--sock".blue()))?
+            .join("agent.sock"),
+    };
+
+    protect_memory();
+
+    let identities = config.identities()?;
+    for key_file in config.secrets.key_files() {
+        identity::unlock_ssh_key(key_file, &config.passphrase)?;
+    }
+
+    let listener = bind(&sock)?;
+    let _guard = SocketGuard(&sock);
+
+    info!(sock = %sock.display(), "agent listening");
+
+    writeln!(
+        stdout(),
+        "MCTL_AGENT_SOCK={}; export MCTL_AGENT_SOCK;",
+        sock.display()
+    )?;
+
+    let (requests, received) = mpsc::channel();
+    thread::spawn(move || accept_connections(&listener, &requests));
+
+    let mut last_request = Instant::now();
+
+    loop {
+        let Some(remaining) = timeout.checked_sub(last_request.elapsed()) else {
+            info!("agent idle, exiting");
+
+            return Ok(());
+        };
+
+        match received.recv_timeout(remaining) {
+            Ok(request) => match answer(&identities, request) {
+                Ok(()) => last_request = Instant::now(),
+                Err(err) => warn!(error = format!("{err:#}"), "couldn't answer request"),
+            },
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => bail!("the agent stopped accepting connections"),
+        }
+    }
+}
+
+/// Keeps the unlocked identities out of the swap and of core dumps.
+fn protect_memory() {
+    if let Err(err) = rustix::process::set_dumpable_behavior(DumpableBehavior::NotDumpable) {
+        warn!(error = %err, "couldn't disable core dumps");
+    }
+
+    let flags = MlockAllFlags::CURRENT | MlockAllFlags::FUTURE | MlockAllFlags::ONFAULT;
+    if let Err(err) = rustix::mm::mlockall(flags) {
+        warn!(
+            error = %err,
+            "couldn't lock the agent memory, the identities could be swapped to disk"
+        );
+    }
+}
+
+/// Binds the socket, replacing a stale one left by an agent that didn't exit cleanly.
+fn bind(sock: &Path) -> eyre::Result<UnixListener> {
+    if let Some(dir) = sock.parent() {
+        temp::create_private_dir(dir)?;
+    }
+
+    match fs::symlink_metadata(sock) {
+        Ok(metadata) => {
+            if !metadata.file_type().is_socket()
+                || metadata.uid() != rustix::process::getuid().as_raw()
+            {
+                return Err(eyre!(
+                    "refusing to replace {}, it isn't a socket owned by the user",
+                    sock.display()
+                ))
+                .note(format!(
+                    "check the {} option or remove the file",
+                    "agent.sock".blue()
+                ));
+            }
+
+            if UnixStream::connect(sock).is_ok() {
+                bail!("an agent is already running on {}", sock.display());
+            }
+
+            debug!(sock = %sock.display(), "removing stale socket");
+
+            fs::remove_file(sock).wrap_err("couldn't remove stale socket")?;
+        }
+        Err(err) if err.kind() == ErrorKind::NotFound => {}
+        Err(err) => {
+            return Err(err).wrap_err_with(|| format!("couldn't check socket: {}", sock.display()));
+        }
+    }
+
+    let listener = UnixListener::bind(sock)
+        .wrap_err_with(|| format!("couldn't bind socket: {}", sock.display()))?;
+
+    fs::set_permissions(sock, fs::Permissions::from_mode(0o600))?;
+
+    Ok(listener)
+}
+
+/// Removes the socket when the agent exits.
+struct SocketGuard<'a>(&'a Path);
+
+impl Drop for SocketGuard<'_> {
+    fn drop(&mut self) {
+        if let Err(err) = fs::remove_file(self.0) {
+            error!(error = %err, "couldn't remove the agent socket");
+        }
+    }
+}
+
+/// Request read from a client, waiting for the answer.
+struct PendingRequest {
+    stream: UnixStream,
+    pid: i32,
+    line: String,
+}
+
+/// Accepts the connections, reading each request on its own thread so a slow client doesn't
+/// block the others.
+fn accept_connections(listener: &UnixListener, requests: &Sender<PendingRequest>) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                error!(error = %err, "couldn't accept connection");
+
+                return;
+            }
+        };
+
+        let requests = requests.clone();
+        thread::spawn(move || match read_request(stream) {
+            Ok(Some(request)) => {
+                // The agent is exiting if nobody receives it
+                let _ = requests.send(request);
+            }
+            Ok(None) => {}
+            Err(err) => warn!(error = format!("{err:#}"), "couldn't read request"),
+        });
+    }
+}
+
+/// Reads the request of a connection, returns none for the connections without one.
+fn read_request(stream: UnixStream) -> eyre::Result<Option<PendingRequest>> {
+    let cred = rustix::net::sockopt::socket_peercred(&stream)
+        .wrap_err("couldn't get the peer credentials")?;
+
+    let pid = cred.pid.as_raw_nonzero().get();
+
+    if cred.uid != rustix::process::getuid() {
+        warn!(
+            uid = cred.uid.as_raw(),
+            pid, "refusing connection from another user"
+        );
+
+        return Ok(None);
+    }
+
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+
+    let mut line = String::new();
+    match BufReader::new(&stream).read_line(&mut line) {
+        Ok(0) => return Ok(None),
+        Ok(_) => {}
+        Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+            return Ok(None);
+        }
+        Err(err) => return Err(err).wrap_err("couldn't read request"),
+    }
+
+    Ok(Some(PendingRequest { stream, pid, line }))
+}
+
+/// Unwraps the file key of the request and writes the response.
+fn answer(identities: &[Box<dyn Identity>], request: PendingRequest) -> eyre::Result<()> {
+    let PendingRequest {
+        mut stream,
+        pid,
+        line,
+    } = request;
+
+    let response = match unwrap_request(identities, &line) {
+        Ok(Some(file_key)) => Response::FileKey(BASE64_STANDARD.encode(file_key.expose_secret())),
+        Ok(None) => Response::NoMatch,
+        Err(err) => Response::Error(format!("{err:#}")),
+    };
+
+    debug!(pid, "answering request");
+
+    let mut out = Zeroizing::new(serde_json::to_vec(&response)?);
+    out.push(b'\n');
+
+    if let Response::FileKey(mut encoded) = response {
+        encoded.zeroize();
+    }
+
+    stream.write_all(&out).wrap_err("couldn't write response")?;
+
+    Ok(())
+}
+
+fn unwrap_request(identities: &[Box<dyn Identity>], line: &str) -> eyre::Result<Option<FileKey>> {
+    let request: Request = serde_json::from_str(line).wrap_err("invalid request")?;
+
+    let stanzas = request
+        .stanzas
+        .into_iter()
+        .map(Stanza::try_from)
+        .collect::<Result<Vec<_>, _>>()
+        .wrap_err("invalid stanza body")?;
+
+    identities
+        .iter()
+        .find_map(|identity| identity.unwrap_stanzas(&stanzas))
+        .transpose()
+        .map_err(eyre::Report::new)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use tempfile::TempDir;
+
+    use std::io::Read;
+
+    use age::{Decryptor, armor::ArmoredReader};
+
+    use super::*;
+    use crate::secret::encrypt;
+
+    #[test]
+    fn decrypt_through_agent() {
+        let tmp = TempDir::new().unwrap();
+
+        let config = Config::mock();
+        let recipients = config.secrets.recipients().unwrap();
+
+        let mut encrypted = Vec::new();
+        encrypt(&recipients, &mut "Hello agent!".as_bytes(), &mut encrypted).unwrap();
+
+        let sock = tmp.path().join("agent/agent.sock");
+        let listener = bind(&sock).unwrap();
+
+        let server = thread::spawn(move || {
+            let identities = config.identities().unwrap();
+
+            // A connection without a request, then the decryption
+            for _ in 0..2 {
+                let (stream, _) = listener.accept().unwrap();
+                if let Some(request) = read_request(stream).unwrap() {
+                    answer(&identities, request).unwrap();
+                }
+            }
+        });
+
+        drop(UnixStream::connect(&sock).unwrap());
+
+        let agent: Vec<Box<dyn Identity>> = vec![Box::new(AgentIdentity { sock: sock.clone() })];
+
+        let decryptor = Decryptor::new(ArmoredReader::new(encrypted.as_slice())).unwrap();
+
+        let mut out = Vec::new();
+        decryptor
+            .decrypt(agent.iter().map(|i| i.as_ref()))
+            .unwrap()
+            .read_to_end(&mut out)
+            .unwrap();
+        assert_eq!(out, b"Hello agent!");
+
+        server.join().unwrap();
+
+        assert!(bind(&sock).is_ok(), "stale socket is replaced");
+    }
+
+    #[test]
+    fn refuse_to_replace_other_files() {
+        let tmp = TempDir::new().unwrap();
+
+        let sock = tmp.path().join("agent.sock");
+        fs::write(&sock, "not a socket").unwrap();
+
+        assert!(bind(&sock).is_err());
+        assert_eq!(fs::read_to_string(&sock).unwrap(), "not a socket");
+    }
+}
@@ -0,0 +1,230 @@
+//! Tar archives of directory trees, stored as a single secret.
+
+use std::{
+    fs::DirBuilder,
+    io::{Read, Write},
+    os::unix::fs::DirBuilderExt,
+    path::{Component, Path},
+};
+
+use eyre::{WrapErr, ensure};
+use tar::{Archive, Builder, Entry, HeaderMode};
+use tracing::debug;
+
+/// Extension of the archive secrets, before the `.pem` or `.age` one.
+pub(crate) const ARCHIVE_EXTENSION: &str = "tar";
+
+/// Writes a tar archive of the directory content, with the modes and modification times.
+///
+/// The symbolic links are archived as links, not followed.
+pub(crate) fn pack<W>(dir: &Path, writer: W) -> eyre::Result<()>
+where
+    W: Write,
+{
+    debug!(dir = %dir.display(), "packing directory");
+
+    let mut builder = Builder::new(writer);
+    builder.mode(HeaderMode::Complete);
+    builder.follow_symlinks(false);
+
+    builder
+        .append_dir_all("", dir)
+        .wrap_err_with(|| format!("couldn't archive directory: {}", dir.display()))?;
+
+    builder.finish().wrap_err("couldn't write the archive")?;
+
+    Ok(())
+}
+
+/// Extracts a tar archive in the directory, refusing the entries outside of it.
+pub(crate) fn unpack<R>(reader: R, dir: &Path) -> eyre::Result<()>
+where
+    R: Read,
+{
+    debug!(dir = %dir.display(), "unpacking archive");
+
+    DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(dir)
+        .wrap_err_with(|| format!("couldn't create directory: {}", dir.display()))?;
+
+    let mut archive = Archive::new(reader);
+    archive.set_preserve_permissions(true);
+    archive.set_preserve_mtime(true);
+
+    // Extracted last, so the content doesn't change their modification time or fail on their
+    // mode, like tar does.
+    let mut directories = Vec::new();
+
+    for entry in archive.entries().wrap_err("couldn't read the archive")? {
+        let entry = entry.wrap_err("couldn't read the archive")?;
+
+        check_entry(&entry)?;
+
+        if entry.header().entry_type().is_dir() {
+            directories.push(entry);
+
+            continue;
+        }
+
+        unpack_entry(entry, dir)?;
+    }
+
+    directories.sort_by(|a, b| b.path_bytes().cmp(&a.path_bytes()));
+
+    for entry in directories {
+        unpack_entry(entry, dir)?;
+    }
+
+    Ok(())
+}
+
+/// Checks the entry and its hard link stay in the directory.
+fn check_entry<R>(entry: &Entry<R>) -> eyre::Result<()>
+where
+    R: Read,
+{
+    let path = entry.path()?;
+
+    ensure!(
+        is_relative_inside(&path),
+        "refusing archive entry outside of the directory: {}",
+        path.display()
+    );
+
+    if entry.header().entry_type().is_hard_link()
+        && let Some(link) = entry.link_name()?
+    {
+        ensure!(
+            is_relative_inside(&link),
+            "refusing hard link outside of the directory: {} -> {}",
+            path.display(),
+            link.display()
+        );
+    }
+
+    Ok(())
+}
+
+fn unpack_entry<R>(mut entry: Entry<R>, dir: &Path) -> eyre::Result<()>
+where
+    R: Read,
+{
+    let path = entry.path()?.into_owned();
+
+    // Also fails if a symbolic link would write outside of the directory
+    let unpacked = entry
+        .unpack_in(dir)
+        .wrap_err_with(|| format!("couldn't extract {}", path.display()))?;
+
+    ensure!(unpacked, "refusing archive entry: {}", path.display());
+
+    Ok(())
+}
+
+fn is_relative_inside(path: &Path) -> bool {
+    path.components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::{self, Permissions},
+        os::unix::fs::{PermissionsExt, symlink},
+        time::{Duration, SystemTime},
+    };
+
+    use pretty_assertions::assert_eq;
+    use tar::{EntryType, Header};
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn pack_and_unpack_directory() {
+        let tmp = TempDir::new().unwrap();
+
+        let src = tmp.path().join("ssh");
+        fs::create_dir_all(src.join("keys")).unwrap();
+        fs::write(src.join("config"), "Host *\n").unwrap();
+        fs::write(src.join("keys/id_ed25519"), "private key").unwrap();
+        fs::set_permissions(src.join("keys/id_ed25519"), Permissions::from_mode(0o600)).unwrap();
+        fs::set_permissions(src.join("keys"), Permissions::from_mode(0o700)).unwrap();
+        symlink("keys/id_ed25519", src.join("id_ed25519")).unwrap();
+
+        let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        fs::File::options()
+            .write(true)
+            .open(src.join("config"))
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+
+        let mut archive = Vec::new();
+        pack(&src, &mut archive).unwrap();
+
+        let dst = tmp.path().join("out");
+        unpack(archive.as_slice(), &dst).unwrap();
+
+        assert_eq!(fs::read_to_string(dst.join("config")).unwrap(), "Host *\n");
+        assert_eq!(
+            fs::read_to_string(dst.join("id_ed25519")).unwrap(),
+            "private key"
+        );
+        assert_eq!(
+            fs::read_link(dst.join("id_ed25519")).unwrap(),
+            Path::new("keys/id_ed25519")
+        );
+
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(&dst.join("keys/id_ed25519")), 0o600);
+        assert_eq!(mode(&dst.join("keys")), 0o700);
+        assert_eq!(mode(&dst), 0o700);
+
+        assert_eq!(
+            fs::metadata(dst.join("config"))
+                .unwrap()
+                .modified()
+                .unwrap(),
+            mtime
+        );
+    }
+
+    fn malicious_archive(path: &[u8], entry_type: EntryType, link: Option<&[u8]>) -> Vec<u8> {
+        let mut header = Header::new_old();
+        header.as_old_mut().name[..path.len()].copy_from_slice(path);
+        if let Some(link) = link {
+            header.as_old_mut().linkname[..link.len()].copy_from_slice(link);
+        }
+        header.set_entry_type(entry_type);
+        header.set_mode(0o644);
+        header.set_size(4);
+        header.set_cksum();
+
+        let mut builder = Builder::new(Vec::new());
+        builder.append(&header, "evil".as_bytes()).unwrap();
+
+        builder.into_inner().unwrap()
+    }
+
+    #[test]
+    fn refuse_path_traversal() {
+        let tmp = TempDir::new().unwrap();
+
+        let dst = tmp.path().join("out");
+
+        for archive in [
+            malicious_archive(b"../evil", EntryType::Regular, None),
+            malicious_archive(b"dir/../../evil", EntryType::Regular, None),
+            malicious_archive(b"/tmp/evil", EntryType::Regular, None),
+            malicious_archive(b"link", EntryType::Link, Some(b"../../etc/passwd")),
+        ] {
+            assert!(unpack(archive.as_slice(), &dst).is_err());
+        }
+
+        assert!(!tmp.path().join("evil").exists());
+        assert!(fs::read_dir(&dst).unwrap().next().is_none());
+    }
+}
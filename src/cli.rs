@@ -1,10 +1,12 @@
 use std::{
+    ffi::OsString,
     io::{Write, stdout},
     path::PathBuf,
 };
 
 use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
-use eyre::eyre;
+use eyre::{bail, eyre};
+use mctl::generate::Generator;
 use tracing::debug;
 
 #[derive(Debug, Parser)]
@@ -25,6 +27,20 @@ pub enum Command {
         #[command(subcommand)]
         command: Secret,
     },
+    /// Git integration to diff and merge the secrets
+    Git {
+        #[command(subcommand)]
+        command: Git,
+    },
+    /// Runs an agent holding the unlocked identities, to decrypt without unlocking them again
+    Agent {
+        /// Path of the socket, defaults to the runtime directory
+        #[arg(long)]
+        sock: Option<PathBuf>,
+        /// Idle time after which the agent exits, like `30m`
+        #[arg(long)]
+        timeout: Option<jiff::Span>,
+    },
     /// Utility functions like shell completions
     Utils {
         #[command(subcommand)]
@@ -34,7 +50,11 @@ pub enum Command {
 
 #[derive(Debug, Subcommand)]
 pub enum Secret {
-    /// Edits a secret
+    /// Edits a secret, or the directory of a tar archive secret
+    ///
+    /// With the `memfd` temporary files, the editor gets a `/proc/self/fd/N` path and must write
+    /// the file in place: editors saving to a new file renamed over the original, like Vim with
+    /// `backupcopy=no` or Helix, can't save the changes.
     Edit {
         /// Allow a secret to be empty.
         #[arg(default_value = "false", long)]
@@ -45,14 +65,169 @@ pub enum Secret {
         /// Path to the secret file
         file: PathBuf,
     },
+    /// Generates a random secret value and encrypts it
+    Generate {
+        /// Kind of value to generate
+        #[arg(long, short, value_enum, default_value = "password")]
+        kind: Kind,
+        /// Characters of the password, or words of the passphrase, or bytes of the token
+        #[arg(long, short)]
+        length: Option<usize>,
+        /// Characters to choose from, only for passwords
+        #[arg(long)]
+        charset: Option<String>,
+        /// Overwrite an existing secret
+        #[arg(default_value = "false", long)]
+        force: bool,
+        /// Path to the secret file
+        file: PathBuf,
+    },
     /// Cats a secret
     Cat {
         /// Path to the secret file
         file: PathBuf,
     },
-    Rotate {
+    /// Encrypts a directory as a tar archive, keeping the modes and modification times
+    Pack {
+        /// Directory to archive
+        dir: PathBuf,
+        /// Path to the secret file, like `pki.tar.age` to edit it as a directory
+        file: PathBuf,
+    },
+    /// Extracts an encrypted tar archive in a directory
+    Unpack {
         /// Path to the secret file
         file: PathBuf,
+        /// Directory to extract the archive to, created if missing
+        dir: PathBuf,
+    },
+    /// Prints a field of a TOML, JSON or dotenv secret
+    Get {
+        /// Path to the secret file
+        file: PathBuf,
+        /// Dot separated path of the field, like `db.password`
+        key: String,
+    },
+    /// Updates a field of a TOML, JSON or dotenv secret
+    Set {
+        /// Read the value from stdin
+        #[arg(default_value = "false", long)]
+        stdin: bool,
+        /// Path to the secret file
+        file: PathBuf,
+        /// Dot separated path of the field, like `db.password`
+        key: String,
+        /// New value of the field
+        #[arg(required_unless_present = "stdin", conflicts_with = "stdin")]
+        value: Option<String>,
+    },
+    /// Runs a command with secrets as environment variables
+    Exec {
+        /// Variable set to the content of a secret
+        #[arg(long = "env", value_name = "NAME=FILE", value_parser = parse_env_var)]
+        vars: Vec<(String, PathBuf)>,
+        /// Dotenv secret with the variables to set
+        #[arg(long = "env-file", value_name = "FILE")]
+        env_files: Vec<PathBuf>,
+        /// Command to run, with its arguments
+        #[arg(required = true, last = true)]
+        command: Vec<OsString>,
+    },
+    /// Renders a template replacing the references to secrets
+    Render {
+        /// File to write the result to, readable only by the owner
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+        /// Template with placeholders like `{{ secret "db.toml.pem" "password" }}`, a literal
+        /// `{{` is written `{{{{`
+        template: PathBuf,
+    },
+    /// Shows the differences between two secrets, or a secret and a git revision
+    Diff {
+        /// Compare the secret with its version at the git revision
+        #[arg(long, conflicts_with = "other")]
+        rev: Option<String>,
+        /// Only list the changed fields, or count the changed lines
+        #[arg(default_value = "false", long)]
+        stat: bool,
+        /// Path to the secret file
+        file: PathBuf,
+        /// Path to the secret to compare with
+        #[arg(required_unless_present = "rev")]
+        other: Option<PathBuf>,
+    },
+    /// Lists the previous versions of a secret kept locally
+    History {
+        /// Path to the secret file
+        file: PathBuf,
+    },
+    /// Puts back a previous version of a secret
+    Restore {
+        /// Number of the version, as listed by the history
+        #[arg(long, short)]
+        version: usize,
+        /// Path to the secret file
+        file: PathBuf,
+    },
+    /// Lists the edits saved when they couldn't be written, like when the editor failed
+    Recover {
+        /// Number of the edit to encrypt back to its secret
+        #[arg(long, short)]
+        apply: Option<usize>,
+        /// Number of the edit to remove
+        #[arg(long, short, conflicts_with = "apply")]
+        discard: Option<usize>,
+    },
+    /// Shows the recipients a secret is encrypted to, without decrypting it
+    Info {
+        /// Path to the secret file
+        file: PathBuf,
+    },
+    /// Lists the secrets of the store
+    List {
+        /// Show the secrets as a tree of their directories
+        #[arg(default_value = "false", long)]
+        tree: bool,
+    },
+    /// Searches the decrypted secrets of the store, printing the matching lines numbers
+    Grep {
+        /// Also print the matching lines, with the secret values
+        #[arg(default_value = "false", long)]
+        show: bool,
+        /// Regular expression to search
+        pattern: String,
+    },
+    /// Lists the secrets not encrypted to the current recipients
+    Status {
+        /// Exit with an error if a secret is out of sync
+        #[arg(default_value = "false", long)]
+        check: bool,
+        /// Directory with the secrets
+        #[arg(default_value = ".")]
+        dir: PathBuf,
+    },
+    /// Re-encrypts secrets to the current recipients
+    Rotate {
+        /// Rotate all the secrets in the given directories
+        #[arg(default_value = "false", long, short)]
+        recursive: bool,
+        /// Paths or glob patterns of the secret files
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+    },
+    /// Manages the recipients
+    Recipients {
+        #[command(subcommand)]
+        command: Recipients,
+    },
+    /// Generates a new identity in the configured key file
+    Init {
+        /// Overwrite an existing key file
+        #[arg(default_value = "false", long)]
+        force: bool,
+        /// Append the public key to the recipients file
+        #[arg(default_value = "false", long)]
+        add_recipient: bool,
     },
 }
 
@@ -78,8 +253,182 @@ impl Secret {
 
                 mctl::secret::edit(file, *allow_empty)
             }
+            Secret::Generate {
+                kind,
+                length,
+                charset,
+                force,
+                file,
+            } => mctl::secret::generate(file, &kind.generator(*length, charset)?, *force),
             Secret::Cat { file } => mctl::secret::cat(file),
-            Secret::Rotate { file } => mctl::secret::rotate(file),
+            Secret::Pack { dir, file } => mctl::secret::pack(dir, file),
+            Secret::Unpack { file, dir } => mctl::secret::unpack(file, dir),
+            Secret::Get { file, key } => mctl::secret::get(file, key),
+            Secret::Set {
+                stdin: _,
+                file,
+                key,
+                value,
+            } => mctl::secret::set(file, key, value.as_deref()),
+            Secret::Exec {
+                vars,
+                env_files,
+                command,
+            } => mctl::secret::exec(vars, env_files, command),
+            Secret::Render { output, template } => {
+                mctl::secret::render(template, output.as_deref())
+            }
+            Secret::Diff {
+                rev,
+                stat,
+                file,
+                other,
+            } => mctl::secret::diff(file, other.as_deref(), rev.as_deref(), *stat),
+            Secret::History { file } => mctl::secret::history(file),
+            Secret::Restore { version, file } => mctl::secret::restore(file, *version),
+            Secret::Recover { apply, discard } => mctl::secret::recover(*apply, *discard),
+            Secret::Info { file } => mctl::secret::info(file),
+            Secret::List { tree } => mctl::secret::list(*tree),
+            Secret::Grep { show, pattern } => mctl::secret::grep(pattern, *show),
+            Secret::Status { check, dir } => mctl::secret::status(dir, *check),
+            Secret::Rotate { recursive, files } => mctl::secret::rotate(files, *recursive),
+            Secret::Init {
+                force,
+                add_recipient,
+            } => mctl::secret::init(*force, *add_recipient),
+            Secret::Recipients { command } => command.run(),
+        }
+    }
+}
+
+fn parse_env_var(value: &str) -> Result<(String, PathBuf), String> {
+    let (name, file) = value
+        .split_once('=')
+        .ok_or_else(|| format!("expected NAME=FILE, got: {value}"))?;
+
+    if name.is_empty() || file.is_empty() {
+        return Err(format!("expected NAME=FILE, got: {value}"));
+    }
+
+    Ok((name.to_string(), PathBuf::from(file)))
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum Kind {
+    /// Random characters, alphanumeric by default
+    Password,
+    /// Random words separated by dashes
+    Passphrase,
+    /// Random bytes encoded as hex
+    Hex,
+    /// Random bytes encoded as base64
+    Base64,
+    /// age X25519 identity, prints the public key
+    X25519,
+    /// OpenSSH ed25519 private key, prints the public key
+    Ed25519,
+    /// Random UUID
+    Uuid,
+}
+
+impl Kind {
+    fn generator(
+        &self,
+        length: Option<usize>,
+        charset: &Option<String>,
+    ) -> eyre::Result<Generator> {
+        let name = self
+            .to_possible_value()
+            .map(|value| value.get_name().to_string());
+
+        if charset.is_some() && !matches!(self, Kind::Password) {
+            bail!(
+                "--charset only applies to passwords, not to {}",
+                name.unwrap_or_default()
+            );
+        }
+
+        let generator = match self {
+            Kind::Password => Generator::Password {
+                length: length.unwrap_or(32),
+                charset: charset.clone(),
+            },
+            Kind::Passphrase => Generator::Passphrase {
+                words: length.unwrap_or(8),
+            },
+            Kind::Hex => Generator::Hex {
+                bytes: length.unwrap_or(32),
+            },
+            Kind::Base64 => Generator::Base64 {
+                bytes: length.unwrap_or(32),
+            },
+            Kind::X25519 | Kind::Ed25519 | Kind::Uuid if length.is_some() => {
+                bail!("--length doesn't apply to {}", name.unwrap_or_default());
+            }
+            Kind::X25519 => Generator::X25519,
+            Kind::Ed25519 => Generator::Ed25519,
+            Kind::Uuid => Generator::Uuid,
+        };
+
+        Ok(generator)
+    }
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Recipients {
+    /// Lists the recipient groups with their expanded members
+    ListGroups,
+}
+
+impl Recipients {
+    fn run(&self) -> eyre::Result<()> {
+        match self {
+            Recipients::ListGroups => mctl::secret::list_groups(),
+        }
+    }
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Git {
+    /// Decrypts a secret to stdout, used to diff the secrets
+    Textconv {
+        /// Path to the secret file
+        file: PathBuf,
+    },
+    /// Merges three versions of a secret, writing the result to the current one
+    MergeDriver {
+        /// Length of the conflict markers
+        #[arg(default_value = "7", long)]
+        marker_size: usize,
+        /// Common ancestor of the versions
+        base: PathBuf,
+        /// Current version, replaced by the merge result
+        current: PathBuf,
+        /// Other branch version
+        other: PathBuf,
+        /// Path of the merged secret, used to choose the recipients
+        path: Option<PathBuf>,
+    },
+    /// Configures the repository to use the diff and merge drivers
+    Setup {
+        /// Patterns of the secret files in the .gitattributes, can be repeated
+        #[arg(default_values = ["*.pem", "*.age"], long = "pattern")]
+        patterns: Vec<String>,
+    },
+}
+
+impl Git {
+    pub(crate) fn run(&self) -> eyre::Result<()> {
+        match self {
+            Git::Textconv { file } => mctl::git::textconv(file),
+            Git::MergeDriver {
+                marker_size,
+                base,
+                current,
+                other,
+                path,
+            } => mctl::git::merge_driver(base, current, other, path.as_deref(), *marker_size),
+            Git::Setup { patterns } => mctl::git::setup(patterns),
         }
     }
 }
@@ -1,18 +1,22 @@
 use std::{
+    collections::BTreeMap,
     env::VarError,
     fs::{self},
     io::{self},
     os::unix::fs::PermissionsExt,
     path::{Path, PathBuf},
+    process::{Command, Stdio},
     str::FromStr,
 };
 
-use age::x25519::{Identity, Recipient};
+use age::{Identity, Recipient, secrecy::SecretString};
 use color_eyre::{Section, owo_colors::OwoColorize};
 use config::FileFormat;
-use eyre::{OptionExt, WrapErr, ensure, eyre};
-use serde::Deserialize;
+use eyre::{OptionExt, WrapErr, bail, ensure, eyre};
+use jiff::Span;
+use serde::{Deserialize, Deserializer};
 use tracing::{debug, error};
+use zeroize::Zeroizing;
 
 #[derive(Debug, Deserialize)]
 #[serde(deny_unknown_fields)]
@@ -20,10 +24,29 @@ pub struct Config {
     pub(crate) editor: String,
     pub(crate) dirs: Directories,
     pub(crate) secrets: Secrets,
+    #[serde(default)]
+    pub(crate) passphrase: Passphrase,
+    #[serde(default)]
+    pub(crate) history: History,
+    #[serde(default)]
+    pub(crate) recovery: Recovery,
+    #[serde(default)]
+    pub(crate) agent: Agent,
+    /// Directory of the mocked configuration, removed with it
+    #[cfg(test)]
+    #[serde(skip)]
+    _test_dir: Option<tempfile::TempDir>,
 }
 
 impl Config {
     pub fn read(custom_conf: Option<&Path>) -> eyre::Result<Self> {
+        Self::read_unchecked(custom_conf)?.validate()
+    }
+
+    /// Reads the configuration without checking the key and recipient files.
+    ///
+    /// Used to create the files in the first place.
+    pub fn read_unchecked(custom_conf: Option<&Path>) -> eyre::Result<Self> {
         let config_dir = dirs::config_local_dir()
             .ok_or_eyre("couldn't determine configuration directory")?
             .join(env!("CARGO_PKG_NAME"));
@@ -60,19 +83,48 @@ impl Config {
             .build()
             .wrap_err("couldn't read the config")?
             .try_deserialize::<Config>()
-            .wrap_err("coldn't read the configuration")?
-            .validate()
+            .wrap_err("coldn't read the configuration")
     }
 
     fn validate(self) -> eyre::Result<Self> {
-        check_private_file(&self.secrets.key_file)?;
+        for key_file in &self.secrets.key_files {
+            check_private_file(key_file)?;
+
+            crate::identity::check_identity_file(key_file, &self.passphrase).with_note(|| {
+                format!(
+                    "Make sure {} is a valid age or SSH private key",
+                    key_file.display().blue()
+                )
+            })?;
+        }
         check_private_file(&self.secrets.recipients_file)?;
 
-        self.secrets.identity()?;
+        let groups = self.secrets.group_names().collect::<Vec<_>>();
+        self.secrets
+            .groups_recipients(&groups)
+            .wrap_err("invalid recipient groups")?;
 
         Ok(self)
     }
 
+    /// Reads the identities, unlocking them with a passphrase if needed.
+    pub(crate) fn identities(&self) -> eyre::Result<Vec<Box<dyn Identity>>> {
+        self.secrets.identities(&self.passphrase)
+    }
+
+    /// Returns the recipients of the user's own identities, to encrypt what only they need.
+    pub(crate) fn own_recipients(&self) -> eyre::Result<Vec<RecipientKey>> {
+        let mut recipients = Vec::new();
+
+        for key_file in &self.secrets.key_files {
+            for public_key in crate::identity::read_public_keys(key_file, &self.passphrase)? {
+                recipients.push(parse_recipient(&public_key)?);
+            }
+        }
+
+        Ok(recipients)
+    }
+
     fn read_env(key: &str) -> Option<String> {
         match std::env::var(key) {
             Ok(value) => Some(value),
@@ -138,81 +190,392 @@ impl Config {
 }
 
 #[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub(crate) struct Secrets {
-    #[serde(default = "default_key_file")]
-    key_file: PathBuf,
+    /// Identity files, `key_file` with a single one is still accepted
+    #[serde(
+        default = "default_key_files",
+        alias = "key_file",
+        deserialize_with = "one_or_many_paths"
+    )]
+    key_files: Vec<PathBuf>,
     #[serde(default = "default_recipients_file")]
     recipients_file: PathBuf,
+    /// Named groups of recipients
+    #[serde(default)]
+    groups: BTreeMap<String, Group>,
+    /// Root directory of the secrets, the current directory by default
+    #[serde(default)]
+    store: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Group {
+    /// Age or SSH recipients of the group
+    #[serde(default)]
+    recipients: Vec<String>,
+    /// Other groups whose members are part of this group
+    #[serde(default)]
+    include: Vec<String>,
 }
 
 impl Secrets {
-    pub(crate) fn identity(&self) -> eyre::Result<Identity> {
-        debug!(file = %self.key_file.display(), "reading identity file");
-
-        let content = zeroize::Zeroizing::new(fs::read_to_string(&self.key_file)?);
-        let key = content
-            .lines()
-            .filter(|s| !(s.starts_with("#") || s.is_empty()))
-            .take(1)
-            .next()
-            .ok_or_eyre("couldn't find key line in key file")?;
-
-        Identity::from_str(key).map_err(|err| {
-            eyre!("{err}")
-                .wrap_err("couldn't read identity file")
-                .note(format!(
-                    "Make sure {} is a valid age private key",
-                    self.key_file.display().blue()
-                ))
-        })
+    pub(crate) fn key_files(&self) -> &[PathBuf] {
+        &self.key_files
+    }
+
+    pub(crate) fn recipients_file(&self) -> &Path {
+        &self.recipients_file
     }
 
-    pub(crate) fn recipients(&self) -> eyre::Result<Vec<Recipient>> {
-        debug!(file = %self.recipients_file.display(), "reading recipients file");
-
-        fs::read_to_string(&self.recipients_file)
-            .wrap_err("couldnt read recipients file")
-            .and_then(|s| {
-                s.lines()
-                    .enumerate()
-                    .filter(|(_, l)| !(l.is_empty() || l.starts_with("#")))
-                    .map(|(n, l)| {
-                        Recipient::from_str(l).map_err(|err| {
-                            eyre!("{err}").wrap_err(format!("couldn't parse {n} recipient line"))
-                        })
-                    })
-                    .collect::<eyre::Result<Vec<Recipient>>>()
-                    .and_then(|recipients| {
-                        ensure!(!recipients.is_empty(), "the recipients file is empty");
-
-                        debug!("read {} recipients", recipients.len());
-
-                        Ok(recipients)
-                    })
+    pub(crate) fn store(&self) -> &Path {
+        self.store.as_deref().unwrap_or(Path::new("."))
+    }
+
+    fn identities(&self, passphrase: &Passphrase) -> eyre::Result<Vec<Box<dyn Identity>>> {
+        let mut identities = Vec::new();
+
+        for key_file in &self.key_files {
+            let keys =
+                crate::identity::read_identity_file(key_file, passphrase).with_note(|| {
+                    format!(
+                        "Make sure {} is a valid age or SSH private key",
+                        key_file.display().blue()
+                    )
+                })?;
+
+            identities.extend(keys);
+        }
+
+        ensure!(!identities.is_empty(), "no key file configured");
+
+        debug!("read {} identities", identities.len());
+
+        Ok(identities)
+    }
+
+    pub(crate) fn recipients(&self) -> eyre::Result<Vec<RecipientKey>> {
+        read_recipients_file(&self.recipients_file)
+    }
+
+    pub(crate) fn group_names(&self) -> impl Iterator<Item = &str> {
+        self.groups.keys().map(String::as_str)
+    }
+
+    /// Returns the recipients of a group, including the members of the nested groups.
+    pub(crate) fn group_members<'a>(&'a self, name: &'a str) -> eyre::Result<Vec<&'a str>> {
+        let mut members = Vec::new();
+        let mut stack = Vec::new();
+
+        self.expand_group(name, &mut stack, &mut members)?;
+
+        Ok(members)
+    }
+
+    fn expand_group<'a>(
+        &'a self,
+        name: &'a str,
+        stack: &mut Vec<&'a str>,
+        members: &mut Vec<&'a str>,
+    ) -> eyre::Result<()> {
+        if stack.contains(&name) {
+            stack.push(name);
+
+            bail!("cycle in recipient groups: {}", stack.join(" -> "));
+        }
+
+        let group = self
+            .groups
+            .get(name)
+            .ok_or_else(|| eyre!("unknown recipient group: {name}"))?;
+
+        stack.push(name);
+
+        for recipient in &group.recipients {
+            if !members.contains(&recipient.as_str()) {
+                members.push(recipient);
+            }
+        }
+
+        for include in &group.include {
+            self.expand_group(include, stack, members)?;
+        }
+
+        stack.pop();
+
+        Ok(())
+    }
+
+    /// Parses the recipients of the given groups.
+    pub(crate) fn groups_recipients<S>(&self, groups: &[S]) -> eyre::Result<Vec<RecipientKey>>
+    where
+        S: AsRef<str>,
+    {
+        let mut members = Vec::new();
+
+        for group in groups {
+            for member in self.group_members(group.as_ref())? {
+                if !members.contains(&member) {
+                    members.push(member);
+                }
+            }
+        }
+
+        members
+            .into_iter()
+            .map(|line| {
+                parse_recipient(line).wrap_err_with(|| format!("invalid group recipient: {line}"))
             })
-            .with_note(|| {
-                format!(
-                    "Make sure {} is a valid recipient file",
-                    self.recipients_file.display().blue()
-                )
+            .collect()
+    }
+}
+
+/// Reads the age X25519 and SSH recipients in a file, one per line.
+pub(crate) fn read_recipients_file(path: &Path) -> eyre::Result<Vec<RecipientKey>> {
+    read_recipient_lines(path)
+        .and_then(|lines| {
+            lines
+                .iter()
+                .map(|(n, l)| parse_recipient(l).wrap_err(format!("on line {n}")))
+                .collect::<eyre::Result<Vec<_>>>()
+        })
+        .with_note(|| {
+            format!(
+                "Make sure {} is a valid recipient file",
+                path.display().blue()
+            )
+        })
+}
+
+/// Reads the non empty and non comment lines of a recipients file, with their line number.
+pub(crate) fn read_recipient_lines(path: &Path) -> eyre::Result<Vec<(usize, String)>> {
+    debug!(file = %path.display(), "reading recipients file");
+
+    let lines = fs::read_to_string(path)
+        .wrap_err("couldnt read recipients file")?
+        .lines()
+        .enumerate()
+        .filter(|(_, l)| !(l.is_empty() || l.starts_with("#")))
+        .map(|(n, l)| (n + 1, l.to_string()))
+        .collect::<Vec<_>>();
+
+    ensure!(!lines.is_empty(), "the recipients file is empty");
+
+    debug!("read {} recipients", lines.len());
+
+    Ok(lines)
+}
+
+/// Parsed recipient with its public key.
+pub(crate) struct RecipientKey {
+    public_key: String,
+    recipient: Box<dyn Recipient + Send>,
+}
+
+impl RecipientKey {
+    pub(crate) fn public_key(&self) -> &str {
+        &self.public_key
+    }
+
+    pub(crate) fn recipient(&self) -> &dyn Recipient {
+        self.recipient.as_ref()
+    }
+
+    /// Short identifier of the public key, recorded for each secret.
+    pub(crate) fn fingerprint(&self) -> String {
+        fingerprint(&self.public_key)
+    }
+}
+
+/// Returns the fingerprint of a public key, ignoring the comment of SSH keys.
+pub(crate) fn fingerprint(public_key: &str) -> String {
+    let key = public_key
+        .split_whitespace()
+        .take(2)
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    let mut hash = blake3::hash(key.as_bytes()).to_hex().to_string();
+    hash.truncate(16);
+
+    hash
+}
+
+/// Parses an age X25519 or SSH recipient.
+pub(crate) fn parse_recipient(line: &str) -> eyre::Result<RecipientKey> {
+    let public_key = line.trim().to_string();
+
+    if line.starts_with("ssh-") {
+        return age::ssh::Recipient::from_str(line)
+            .map(|r| RecipientKey {
+                public_key,
+                recipient: Box::new(r),
             })
+            .map_err(|err| eyre!("{err:?}").wrap_err("couldn't parse SSH recipient"));
     }
+
+    age::x25519::Recipient::from_str(line)
+        .map(|r| RecipientKey {
+            public_key,
+            recipient: Box::new(r),
+        })
+        .map_err(|err| eyre!("{err}").wrap_err("couldn't parse age recipient"))
 }
 
 impl Default for Secrets {
     fn default() -> Self {
         Self {
-            key_file: default_key_file(),
+            key_files: default_key_files(),
             recipients_file: default_recipients_file(),
+            groups: BTreeMap::new(),
+            store: None,
         }
     }
 }
 
+#[derive(Debug, Default, Clone, Deserialize)]
+pub(crate) struct Passphrase {
+    /// Command printing the passphrase to unlock the identity files
+    command: Option<String>,
+}
+
+impl Passphrase {
+    /// Requests the passphrase for a key file from the command or the terminal.
+    pub(crate) fn request(&self, key_file: &Path, prompt: &str) -> eyre::Result<SecretString> {
+        let Some(command) = &self.command else {
+            return crate::identity::prompt_passphrase(prompt);
+        };
+
+        debug!(command, "running passphrase command");
+
+        let out = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .env("MCTL_KEY_FILE", key_file)
+            .stdin(Stdio::inherit())
+            .stderr(Stdio::inherit())
+            .output()
+            .wrap_err("couldn't run the passphrase command")?;
+
+        let stdout = Zeroizing::new(out.stdout);
+
+        ensure!(
+            out.status.success(),
+            "passphrase command exited with status: {}",
+            out.status
+        );
+
+        let passphrase = str::from_utf8(&stdout)
+            .wrap_err("passphrase is not valid UTF-8")?
+            .trim_end_matches(['\n', '\r']);
+
+        Ok(SecretString::from(passphrase))
+    }
+}
+
+/// Retention of the previous versions of the secrets.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct History {
+    /// Number of previous versions kept for each secret, 0 disables the history
+    #[serde(default = "default_history_keep")]
+    pub(crate) keep: usize,
+    /// Maximum age of the previous versions, like `30 days`
+    #[serde(default)]
+    pub(crate) max_age: Option<Span>,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self {
+            keep: default_history_keep(),
+            max_age: None,
+        }
+    }
+}
+
+fn default_history_keep() -> usize {
+    10
+}
+
+/// Cleanup of the edits interrupted by a crash.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Recovery {
+    /// Age after which the plaintext temporary files left in the cache are removed, like `1 day`
+    #[serde(default = "default_orphan_age")]
+    pub(crate) orphan_age: Span,
+}
+
+impl Default for Recovery {
+    fn default() -> Self {
+        Self {
+            orphan_age: default_orphan_age(),
+        }
+    }
+}
+
+fn default_orphan_age() -> Span {
+    Span::new().days(1)
+}
+
+/// Agent holding the unlocked identities.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Agent {
+    /// Socket of the agent used to decrypt, also set with `MCTL_AGENT_SOCK`
+    #[serde(default)]
+    pub(crate) sock: Option<PathBuf>,
+    /// Idle time after which the agent exits, like `1 hour`
+    #[serde(default = "default_agent_timeout")]
+    pub(crate) timeout: Span,
+}
+
+impl Default for Agent {
+    fn default() -> Self {
+        Self {
+            sock: None,
+            timeout: default_agent_timeout(),
+        }
+    }
+}
+
+fn default_agent_timeout() -> Span {
+    Span::new().hours(1)
+}
+
 #[derive(Debug, Deserialize)]
 pub(crate) struct Directories {
     /// Cache directory
     #[serde(default = "default_cache_dir")]
     cache: PathBuf,
+    /// Directory with the previous versions of the secrets, inside the cache by default
+    #[serde(default)]
+    history: Option<PathBuf>,
+    /// Where the secrets are decrypted for editing
+    #[serde(default)]
+    pub(crate) temp: TempStrategy,
+}
+
+/// Location of the plaintext temporary files.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum TempStrategy {
+    /// The runtime directory, then `/dev/shm`, then the cache directory
+    #[default]
+    Auto,
+    /// The `$XDG_RUNTIME_DIR` directory
+    Runtime,
+    /// The `/dev/shm` directory
+    Shm,
+    /// An anonymous memory file, passed to the editor as `/proc/self/fd/N`
+    ///
+    /// The editor must write the file in place, the ones saving to a new file renamed over the
+    /// original (Vim with `backupcopy=no`, Helix, most graphical editors) can't save the changes.
+    Memfd,
+    /// The cache directory, usually on disk
+    Cache,
 }
 
 impl Directories {
@@ -223,6 +586,34 @@ impl Directories {
 
         Ok(&self.cache)
     }
+
+    pub(crate) fn history(&self) -> PathBuf {
+        self.history
+            .clone()
+            .unwrap_or_else(|| self.cache.join("history"))
+    }
+}
+
+/// Reads a single path as a list, for the options that used to accept only one.
+fn one_or_many_paths<'de, D>(deserializer: D) -> Result<Vec<PathBuf>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(PathBuf),
+        Many(Vec<PathBuf>),
+    }
+
+    match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(path) => Ok(vec![path]),
+        OneOrMany::Many(paths) => Ok(paths),
+    }
+}
+
+fn default_key_files() -> Vec<PathBuf> {
+    vec![default_key_file()]
 }
 
 fn default_key_file() -> PathBuf {
@@ -289,20 +680,49 @@ pub(crate) mod tests {
         pub(crate) fn mock() -> Self {
             let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
 
+            // Keeps the cache, history and temporary files out of the home directory
+            let test_dir = tempfile::TempDir::new().unwrap();
+
             let cfg = Self {
                 editor: "cat".to_string(),
                 dirs: Directories {
-                    cache: default_cache_dir(),
+                    cache: test_dir.path().join("cache"),
+                    history: None,
+                    temp: TempStrategy::Cache,
                 },
                 secrets: Secrets {
-                    key_file: dir.join("assets/test.key.txt"),
+                    key_files: vec![dir.join("assets/test.key.txt")],
                     recipients_file: dir.join("assets/test.recipients.txt"),
+                    groups: BTreeMap::new(),
+                    store: None,
                 },
+                passphrase: Passphrase::default(),
+                history: History::default(),
+                recovery: Recovery::default(),
+                agent: Agent::default(),
+                _test_dir: Some(test_dir),
             };
 
             cfg.validate().unwrap()
         }
 
+        /// Configuration with the key and recipients files in a directory, that may not exist.
+        pub(crate) fn in_dir(mut self, dir: &Path) -> Self {
+            self.secrets.key_files = vec![dir.join("age/key.txt")];
+            self.secrets.recipients_file = dir.join("age/recipients.txt");
+
+            self
+        }
+
+        pub(crate) fn use_ssh_key(mut self) -> Self {
+            let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+
+            self.secrets.key_files = vec![dir.join("assets/test.ssh.key")];
+            self.secrets.recipients_file = dir.join("assets/test.recipients.ssh.txt");
+
+            self.validate().unwrap()
+        }
+
         pub(crate) fn use_other_recipient(mut self) -> Self {
             let mut dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
             dir.push("assets/test.recipients.2.txt");
@@ -311,5 +731,147 @@ pub(crate) mod tests {
 
             self.validate().unwrap()
         }
+
+        pub(crate) fn with_key_files(mut self, key_files: Vec<PathBuf>) -> Self {
+            self.secrets.key_files = key_files;
+
+            self.validate().unwrap()
+        }
+
+        pub(crate) fn with_passphrase_command(mut self, command: &str) -> Self {
+            self.passphrase.command = Some(command.to_string());
+
+            self.validate().unwrap()
+        }
+
+        pub(crate) fn with_group(
+            mut self,
+            name: &str,
+            recipients: &[&str],
+            include: &[&str],
+        ) -> Self {
+            self.secrets.groups.insert(
+                name.to_string(),
+                Group {
+                    recipients: recipients.iter().map(|r| r.to_string()).collect(),
+                    include: include.iter().map(|r| r.to_string()).collect(),
+                },
+            );
+
+            self
+        }
+
+        pub(crate) fn with_store(mut self, dir: &Path) -> Self {
+            self.secrets.store = Some(dir.to_path_buf());
+
+            self
+        }
+
+        pub(crate) fn with_cache_dir(mut self, dir: &Path) -> Self {
+            self.dirs.cache = dir.to_path_buf();
+
+            self
+        }
+
+        pub(crate) fn with_temp(mut self, temp: TempStrategy) -> Self {
+            self.dirs.temp = temp;
+
+            self
+        }
+
+        pub(crate) fn with_history_dir(mut self, dir: &Path) -> Self {
+            self.dirs.history = Some(dir.to_path_buf());
+
+            self
+        }
+
+        pub(crate) fn with_recipients_file(mut self, recipients_file: PathBuf) -> Self {
+            self.secrets.recipients_file = recipients_file;
+
+            self.validate().unwrap()
+        }
+    }
+
+    const ALICE: &str = "age1zt4juc2eds5w7jc5rjfnfs9l9zpq6awmfup2ypzthg4km7f8a3lqsvh5kt";
+    const BOB: &str = "age18kpe8fr5va7n837kxc3v2dpsh73gptt6wm3xsguj45l9xksph56q55jegd";
+
+    #[test]
+    fn recipients_of_own_identities() {
+        let own = |config: Config| {
+            config
+                .own_recipients()
+                .unwrap()
+                .iter()
+                .map(|recipient| recipient.public_key().to_string())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(own(Config::mock()), [ALICE]);
+
+        let ssh = own(Config::mock().use_ssh_key());
+        assert_eq!(ssh.len(), 1);
+        assert!(ssh[0].starts_with(
+            "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIOf3Lgx5TIuGhVc2UK6QIy0Mf0Lptskwmiw+1Il3C3pe"
+        ));
+    }
+
+    fn parse_secrets(content: &str) -> eyre::Result<Secrets> {
+        config::Config::builder()
+            .add_source(config::File::from_str(content, FileFormat::Toml))
+            .build()?
+            .try_deserialize()
+            .map_err(Into::into)
+    }
+
+    #[test]
+    fn read_single_key_file() {
+        let secrets = parse_secrets(r#"key_file = "/keys/old.txt""#).unwrap();
+        assert_eq!(secrets.key_files, [PathBuf::from("/keys/old.txt")]);
+
+        let secrets = parse_secrets(r#"key_files = ["/keys/a.txt", "/keys/b.txt"]"#).unwrap();
+        assert_eq!(
+            secrets.key_files,
+            [PathBuf::from("/keys/a.txt"), PathBuf::from("/keys/b.txt")]
+        );
+
+        assert!(parse_secrets(r#"key_fils = ["/keys/a.txt"]"#).is_err());
+    }
+
+    #[test]
+    fn expand_nested_groups() {
+        let config = Config::mock()
+            .with_group("admins", &[ALICE], &[])
+            .with_group("ops", &[BOB, ALICE], &["admins"])
+            .with_group("ci", &[], &["ops"]);
+
+        assert_eq!(config.secrets.group_members("ci").unwrap(), [BOB, ALICE]);
+
+        let recipients = config.secrets.groups_recipients(&["admins", "ci"]).unwrap();
+        assert_eq!(recipients.len(), 2);
+
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn detect_group_cycles() {
+        let config = Config::mock()
+            .with_group("a", &[ALICE], &["b"])
+            .with_group("b", &[], &["c"])
+            .with_group("c", &[BOB], &["a"]);
+
+        let err = config.secrets.group_members("a").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "cycle in recipient groups: a -> b -> c -> a"
+        );
+
+        let err = Config::mock()
+            .with_group("a", &[], &["missing"])
+            .secrets
+            .group_members("a")
+            .unwrap_err();
+        assert_eq!(err.to_string(), "unknown recipient group: missing");
+
+        assert!(config.validate().is_err());
     }
 }
@@ -0,0 +1,615 @@
+//! Structured secrets, to read and update a single field.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::mem;
+
+use eyre::{OptionExt, WrapErr, bail, ensure, eyre};
+use serde::Serialize;
+use serde_json::{Serializer, ser::PrettyFormatter};
+use toml_edit::{DocumentMut, Item};
+use zeroize::Zeroizing;
+
+/// Format of a structured secret, from the extension before the `.pem` one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Format {
+    Toml,
+    Json,
+    Dotenv,
+}
+
+impl Format {
+    pub(crate) fn from_extension(ext: Option<&str>) -> eyre::Result<Self> {
+        match ext {
+            Some("toml") => Ok(Format::Toml),
+            Some("json") => Ok(Format::Json),
+            Some("env") => Ok(Format::Dotenv),
+            Some(ext) => bail!("unsupported secret format: {ext}"),
+            None => bail!("the secret has no format extension"),
+        }
+    }
+}
+
+/// Parsed structured secret.
+///
+/// TOML and dotenv files keep their formatting and comments, JSON files keep the key order and
+/// their indentation, or stay on a single line.
+pub(crate) enum Document {
+    Toml(DocumentMut),
+    Json {
+        value: serde_json::Value,
+        /// Indentation of the nested values, none for a compact document
+        indent: Option<String>,
+        newline: bool,
+    },
+    Dotenv(Vec<String>),
+}
+
+impl Document {
+    pub(crate) fn parse(format: Format, content: &str) -> eyre::Result<Self> {
+        let document = match format {
+            Format::Toml => Document::Toml(content.parse().wrap_err("couldn't parse TOML")?),
+            Format::Json => Document::Json {
+                value: serde_json::from_str(content).wrap_err("couldn't parse JSON")?,
+                indent: json_indent(content).map(str::to_string),
+                newline: content.ends_with('\n'),
+            },
+            Format::Dotenv => Document::Dotenv(content.lines().map(str::to_string).collect()),
+        };
+
+        Ok(document)
+    }
+
+    /// Returns the value at the dot separated key path.
+    ///
+    /// Strings are returned unquoted, other values in the format of the document.
+    pub(crate) fn get(&self, key: &str) -> eyre::Result<String> {
+        let path = split_key(key)?;
+
+        match self {
+            Document::Toml(doc) => {
+                let item =
+                    toml_get(doc.as_item(), &path).ok_or_else(|| eyre!("key not found: {key}"))?;
+
+                match item {
+                    Item::Value(toml_edit::Value::String(value)) => Ok(value.value().clone()),
+                    Item::Value(value) => Ok(toml_value(value)),
+                    item => Ok(item.to_string()),
+                }
+            }
+            Document::Json { value: doc, .. } => {
+                let value = json_get(doc, &path).ok_or_else(|| eyre!("key not found: {key}"))?;
+
+                match value {
+                    serde_json::Value::String(value) => Ok(value.clone()),
+                    value => serde_json::to_string_pretty(value).map_err(Into::into),
+                }
+            }
+            Document::Dotenv(lines) => {
+                let (_, value) = lines
+                    .iter()
+                    .rev()
+                    .find_map(|line| dotenv_entry(line).filter(|(name, _)| *name == key))
+                    .ok_or_else(|| eyre!("key not found: {key}"))?;
+
+                Ok(dotenv_unquote(value))
+            }
+        }
+    }
+
+    /// Sets the value at the dot separated key path, creating the missing tables.
+    ///
+    /// The value is stored as a string, unless the current one has a different type. In that
+    /// case it's parsed in the format of the document.
+    pub(crate) fn set(&mut self, key: &str, value: &str) -> eyre::Result<()> {
+        let path = split_key(key)?;
+
+        match self {
+            Document::Toml(doc) => toml_set(doc.as_item_mut(), &path, value),
+            Document::Json { value: doc, .. } => json_set(doc, &path, value),
+            Document::Dotenv(lines) => {
+                ensure!(path.len() == 1, "dotenv keys cannot be nested: {key}");
+
+                let entry = lines
+                    .iter_mut()
+                    .rev()
+                    .find(|line| dotenv_entry(line).is_some_and(|(name, _)| name == key));
+
+                match entry {
+                    Some(line) => {
+                        let export = line.trim_start().starts_with("export ");
+                        let comment = dotenv_entry(line)
+                            .map(|(_, value)| dotenv_comment(value).to_string())
+                            .unwrap_or_default();
+
+                        *line = dotenv_line(export, key, value);
+                        line.push_str(&comment);
+                    }
+                    None => lines.push(dotenv_line(false, key, value)),
+                }
+
+                Ok(())
+            }
+        }
+    }
+
+    /// Returns the values of the document by dot separated key path.
+    pub(crate) fn fields(&self) -> BTreeMap<String, String> {
+        let mut fields = BTreeMap::new();
+
+        match self {
+            Document::Toml(doc) => toml_fields(doc.as_item(), String::new(), &mut fields),
+            Document::Json { value, .. } => json_fields(value, String::new(), &mut fields),
+            Document::Dotenv(lines) => fields.extend(
+                lines
+                    .iter()
+                    .filter_map(|line| dotenv_entry(line))
+                    .map(|(key, value)| (key.to_string(), dotenv_unquote(value))),
+            ),
+        }
+
+        fields
+    }
+
+    pub(crate) fn serialize(&self) -> eyre::Result<Zeroizing<String>> {
+        match self {
+            Document::Toml(doc) => Ok(Zeroizing::new(doc.to_string())),
+            Document::Json {
+                value,
+                indent,
+                newline,
+            } => {
+                let mut content = Zeroizing::new(Vec::new());
+
+                match indent {
+                    Some(indent) => {
+                        let formatter = PrettyFormatter::with_indent(indent.as_bytes());
+
+                        value
+                            .serialize(&mut Serializer::with_formatter(&mut *content, formatter))?;
+                    }
+                    None => serde_json::to_writer(&mut *content, value)?,
+                }
+
+                if *newline {
+                    content.push(b'\n');
+                }
+
+                Ok(Zeroizing::new(String::from_utf8(mem::take(&mut *content))?))
+            }
+            Document::Dotenv(lines) => {
+                let mut content = Zeroizing::new(lines.join("\n"));
+                content.push('\n');
+
+                Ok(content)
+            }
+        }
+    }
+}
+
+fn split_key(key: &str) -> eyre::Result<Vec<&str>> {
+    let path = key.split('.').collect::<Vec<_>>();
+
+    ensure!(
+        path.iter().all(|key| !key.is_empty()),
+        "invalid key path: {key}"
+    );
+
+    Ok(path)
+}
+
+fn join_key(prefix: &str, key: &str) -> String {
+    if prefix.is_empty() {
+        key.to_string()
+    } else {
+        format!("{prefix}.{key}")
+    }
+}
+
+fn toml_fields(item: &Item, prefix: String, fields: &mut BTreeMap<String, String>) {
+    if let Some(table) = item.as_table_like() {
+        for (key, item) in table.iter() {
+            toml_fields(item, join_key(&prefix, key), fields);
+        }
+
+        return;
+    }
+
+    match item {
+        Item::ArrayOfTables(array) => {
+            for (idx, table) in array.iter().enumerate() {
+                for (key, item) in table.iter() {
+                    toml_fields(
+                        item,
+                        join_key(&join_key(&prefix, &idx.to_string()), key),
+                        fields,
+                    );
+                }
+            }
+        }
+        Item::Value(value) => {
+            fields.insert(prefix, toml_value(value));
+        }
+        Item::None | Item::Table(_) => {}
+    }
+}
+
+/// Returns the indentation of the first nested line, none if the document is on a single line.
+fn json_indent(content: &str) -> Option<&str> {
+    content
+        .trim()
+        .lines()
+        .skip(1)
+        .find(|line| !line.trim().is_empty())
+        .map(|line| &line[..line.len() - line.trim_start().len()])
+}
+
+fn json_fields(value: &serde_json::Value, prefix: String, fields: &mut BTreeMap<String, String>) {
+    match value {
+        serde_json::Value::Object(object) => {
+            for (key, value) in object {
+                json_fields(value, join_key(&prefix, key), fields);
+            }
+        }
+        serde_json::Value::Array(array) => {
+            for (idx, value) in array.iter().enumerate() {
+                json_fields(value, join_key(&prefix, &idx.to_string()), fields);
+            }
+        }
+        value => {
+            fields.insert(prefix, value.to_string());
+        }
+    }
+}
+
+/// Formats a value without its decor, like the spaces and the comments around it.
+fn toml_value(value: &toml_edit::Value) -> String {
+    value.clone().decorated("", "").to_string()
+}
+
+fn toml_get<'a>(item: &'a Item, path: &[&str]) -> Option<&'a Item> {
+    path.iter().try_fold(item, |item, key| {
+        match key.parse::<usize>() {
+            Ok(idx) if item.is_array() || item.is_array_of_tables() => item.get(idx),
+            _ => item.get(*key),
+        }
+        .filter(|item| !item.is_none())
+    })
+}
+
+fn toml_set(item: &mut Item, path: &[&str], value: &str) -> eyre::Result<()> {
+    let (last, parents) = path.split_last().ok_or_eyre("empty key path")?;
+
+    let mut table = item;
+    for key in parents {
+        table = match key.parse::<usize>() {
+            Ok(idx) if table.is_array() || table.is_array_of_tables() => table
+                .get_mut(idx)
+                .ok_or_else(|| eyre!("index out of bounds: {idx}"))?,
+            _ => {
+                let table = table
+                    .as_table_like_mut()
+                    .ok_or_else(|| eyre!("{key} is not in a table"))?;
+
+                table
+                    .entry(key)
+                    .or_insert_with(|| Item::Table(toml_edit::Table::new()))
+            }
+        };
+    }
+
+    let table = table
+        .as_table_like_mut()
+        .ok_or_else(|| eyre!("{last} is not in a table"))?;
+
+    let mut new = match table.get(last) {
+        None | Some(Item::Value(toml_edit::Value::String(_))) => toml_edit::Value::from(value),
+        Some(Item::Value(_)) => value
+            .parse::<toml_edit::Value>()
+            .wrap_err_with(|| format!("invalid TOML value for {last}"))?,
+        Some(_) => bail!("{last} is a table, only values can be set"),
+    };
+
+    if let Some(current) = table.get(last).and_then(Item::as_value) {
+        *new.decor_mut() = current.decor().clone();
+    }
+
+    table.insert(last, Item::Value(new));
+
+    Ok(())
+}
+
+fn json_get<'a>(value: &'a serde_json::Value, path: &[&str]) -> Option<&'a serde_json::Value> {
+    path.iter().try_fold(value, |value, key| match value {
+        serde_json::Value::Array(array) => array.get(key.parse::<usize>().ok()?),
+        serde_json::Value::Object(object) => object.get(*key),
+        _ => None,
+    })
+}
+
+fn json_set(value: &mut serde_json::Value, path: &[&str], new: &str) -> eyre::Result<()> {
+    let (last, parents) = path.split_last().ok_or_eyre("empty key path")?;
+
+    let mut object = value;
+    for key in parents {
+        object = match object {
+            serde_json::Value::Array(array) => {
+                let idx = key
+                    .parse::<usize>()
+                    .wrap_err_with(|| format!("invalid array index: {key}"))?;
+
+                array
+                    .get_mut(idx)
+                    .ok_or_else(|| eyre!("index out of bounds: {idx}"))?
+            }
+            serde_json::Value::Object(object) => object
+                .entry(*key)
+                .or_insert_with(|| serde_json::Value::Object(serde_json::Map::new())),
+            _ => bail!("{key} is not in an object"),
+        };
+    }
+
+    let serde_json::Value::Object(object) = object else {
+        bail!("{last} is not in an object");
+    };
+
+    let new = match object.get(*last) {
+        None | Some(serde_json::Value::String(_)) => serde_json::Value::String(new.to_string()),
+        Some(serde_json::Value::Object(_) | serde_json::Value::Array(_)) => {
+            bail!("{last} is an object or array, only values can be set")
+        }
+        Some(_) => {
+            serde_json::from_str(new).wrap_err_with(|| format!("invalid JSON value for {last}"))?
+        }
+    };
+
+    object.insert(last.to_string(), new);
+
+    Ok(())
+}
+
+/// Returns the variables of a dotenv file, in order.
+pub(crate) fn dotenv_vars(content: &str) -> Vec<(String, String)> {
+    content
+        .lines()
+        .filter_map(dotenv_entry)
+        .map(|(key, value)| (key.to_string(), dotenv_unquote(value)))
+        .collect()
+}
+
+/// Returns the key and the raw value of a dotenv line.
+fn dotenv_entry(line: &str) -> Option<(&str, &str)> {
+    let line = line.trim_start();
+
+    if line.starts_with('#') {
+        return None;
+    }
+
+    let line = line.strip_prefix("export ").unwrap_or(line);
+    let (key, value) = line.split_once('=')?;
+
+    Some((key.trim(), value.trim()))
+}
+
+fn dotenv_unquote(value: &str) -> String {
+    if let Some(value) = value.strip_prefix('\'') {
+        return value
+            .split_once('\'')
+            .map_or(value, |(value, _)| value)
+            .to_string();
+    }
+
+    let Some(value) = value.strip_prefix('"') else {
+        // Strip the inline comments of unquoted values
+        return value
+            .split_once(" #")
+            .map_or(value, |(value, _)| value)
+            .trim_end()
+            .to_string();
+    };
+
+    let mut unquoted = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => break,
+            '\\' => match chars.next() {
+                Some('n') => unquoted.push('\n'),
+                Some(c) => unquoted.push(c),
+                None => unquoted.push('\\'),
+            },
+            c => unquoted.push(c),
+        }
+    }
+
+    unquoted
+}
+
+/// Returns the inline comment after a raw value, with the spaces before it.
+fn dotenv_comment(value: &str) -> &str {
+    let end = if let Some(quoted) = value.strip_prefix('\'') {
+        quoted.find('\'').map(|idx| idx + 2)
+    } else if let Some(quoted) = value.strip_prefix('"') {
+        let mut escaped = false;
+
+        quoted
+            .char_indices()
+            .find(|(_, c)| {
+                let closing = *c == '"' && !escaped;
+                escaped = *c == '\\' && !escaped;
+
+                closing
+            })
+            .map(|(idx, _)| idx + 2)
+    } else {
+        value.find(" #")
+    };
+
+    let rest = end.map_or("", |end| &value[end..]);
+
+    if rest.trim_start().starts_with('#') {
+        rest
+    } else {
+        ""
+    }
+}
+
+fn dotenv_line(export: bool, key: &str, value: &str) -> String {
+    let mut line = String::new();
+
+    if export {
+        line.push_str("export ");
+    }
+
+    line.push_str(key);
+    line.push('=');
+
+    let plain = value
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || "_-.,:/@+%".contains(c));
+
+    if plain {
+        line.push_str(value);
+
+        return line;
+    }
+
+    line.push('"');
+    for c in value.chars() {
+        match c {
+            '\n' => line.push_str("\\n"),
+            '"' | '\\' | '$' | '`' => {
+                let _ = write!(line, "\\{c}");
+            }
+            c => line.push(c),
+        }
+    }
+    line.push('"');
+
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn toml_keeps_formatting() {
+        let content = r#"# Database
+[db]
+user = "admin" # the owner
+password = 'old'
+port = 5432 # the port
+tags = ["a", "b"] # replicas
+
+[[replicas]]
+host = "a"
+"#;
+
+        let mut doc = Document::parse(Format::Toml, content).unwrap();
+
+        assert_eq!(doc.get("db.user").unwrap(), "admin");
+        assert_eq!(doc.get("db.port").unwrap(), "5432");
+        assert_eq!(doc.get("db.tags").unwrap(), r#"["a", "b"]"#);
+        assert_eq!(doc.get("replicas.0.host").unwrap(), "a");
+        assert_eq!(doc.fields()["db.port"], "5432");
+        assert!(doc.get("db.host").is_err());
+
+        doc.set("db.password", "new \"pass\"").unwrap();
+        doc.set("db.port", "5433").unwrap();
+        doc.set("db.user", "root").unwrap();
+        doc.set("cache.url", "redis://").unwrap();
+
+        assert!(doc.set("db", "value").is_err());
+        assert!(doc.set("db.port", "not a number").is_err());
+
+        let expected = r#"# Database
+[db]
+user = "root" # the owner
+password = 'new "pass"'
+port = 5433 # the port
+tags = ["a", "b"] # replicas
+
+[[replicas]]
+host = "a"
+
+[cache]
+url = "redis://"
+"#;
+
+        assert_eq!(doc.serialize().unwrap().as_str(), expected);
+    }
+
+    #[test]
+    fn json_keeps_key_order() {
+        let content = r#"{"user": "admin", "port": 5432, "hosts": [{"name": "a"}]}"#;
+
+        let mut doc = Document::parse(Format::Json, content).unwrap();
+
+        assert_eq!(doc.get("hosts.0.name").unwrap(), "a");
+        assert_eq!(doc.get("port").unwrap(), "5432");
+
+        doc.set("user", "root").unwrap();
+        doc.set("port", "5433").unwrap();
+        doc.set("hosts.0.name", "b").unwrap();
+        doc.set("tls.enabled", "true").unwrap();
+
+        // A compact document stays compact
+        let expected =
+            r#"{"user":"root","port":5433,"hosts":[{"name":"b"}],"tls":{"enabled":"true"}}"#;
+
+        assert_eq!(doc.serialize().unwrap().as_str(), expected);
+    }
+
+    #[test]
+    fn json_keeps_indentation() {
+        let content =
+            "{\n    \"user\": \"admin\",\n    \"db\": {\n        \"port\": 5432\n    }\n}\n";
+
+        let mut doc = Document::parse(Format::Json, content).unwrap();
+
+        doc.set("db.port", "5433").unwrap();
+
+        assert_eq!(
+            doc.serialize().unwrap().as_str(),
+            content.replace("5432", "5433")
+        );
+    }
+
+    #[test]
+    fn dotenv_keeps_comments() {
+        let content = r#"# Database
+export DB_USER=admin
+DB_PASSWORD="p\"ass" # quoted
+DB_HOST='localhost' # single
+DB_NAME=app # unquoted
+"#;
+
+        let mut doc = Document::parse(Format::Dotenv, content).unwrap();
+
+        assert_eq!(doc.get("DB_USER").unwrap(), "admin");
+        assert_eq!(doc.get("DB_PASSWORD").unwrap(), "p\"ass");
+        assert_eq!(doc.get("DB_HOST").unwrap(), "localhost");
+
+        doc.set("DB_USER", "root").unwrap();
+        doc.set("DB_PASSWORD", "with space $HOME").unwrap();
+        doc.set("DB_HOST", "db.local").unwrap();
+        doc.set("DB_NAME", "other").unwrap();
+        doc.set("DB_PORT", "5432").unwrap();
+
+        assert!(doc.set("DB.PORT", "5432").is_err());
+
+        let expected = r#"# Database
+export DB_USER=root
+DB_PASSWORD="with space \$HOME" # quoted
+DB_HOST=db.local # single
+DB_NAME=other # unquoted
+DB_PORT=5432
+"#;
+
+        assert_eq!(doc.serialize().unwrap().as_str(), expected);
+        assert_eq!(doc.get("DB_PASSWORD").unwrap(), "with space $HOME");
+    }
+}
@@ -0,0 +1,159 @@
+//! Encoding of the secret files: armored or binary age, and compression of the plaintext.
+
+use std::{
+    io::{Read, Write},
+    path::Path,
+};
+
+use serde::Deserialize;
+use zeroize::Zeroizing;
+
+/// Prefix of the compressed plaintexts, inside the encryption.
+const ZSTD_HEADER: &[u8] = b"mctl-zstd/v1\n";
+
+/// Magic number of the zstd frames, following the header.
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+/// Extension of the binary secrets.
+pub(crate) const BINARY_EXTENSION: &str = "age";
+
+/// Format of the age file.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum OutputFormat {
+    /// ASCII armor, the `.pem` files
+    #[default]
+    Armored,
+    /// Raw age, the `.age` files
+    Binary,
+}
+
+impl From<OutputFormat> for age::armor::Format {
+    fn from(value: OutputFormat) -> Self {
+        match value {
+            OutputFormat::Armored => age::armor::Format::AsciiArmor,
+            OutputFormat::Binary => age::armor::Format::Binary,
+        }
+    }
+}
+
+/// How a secret is written, the decryption detects it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct Encoding {
+    pub(crate) format: OutputFormat,
+    pub(crate) compress: bool,
+}
+
+impl Encoding {
+    /// Binary for the `.age` files, armored otherwise.
+    pub(crate) fn from_extension(secret: &Path) -> Self {
+        let format = if secret
+            .extension()
+            .is_some_and(|ext| ext == BINARY_EXTENSION)
+        {
+            OutputFormat::Binary
+        } else {
+            OutputFormat::Armored
+        };
+
+        Self {
+            format,
+            compress: false,
+        }
+    }
+}
+
+/// Writes the plaintext with the callback, compressing it if needed.
+pub(crate) fn write_plaintext<W, F>(compress: bool, writer: &mut W, write: F) -> eyre::Result<()>
+where
+    W: Write,
+    F: FnOnce(&mut dyn Write) -> eyre::Result<()>,
+{
+    if !compress {
+        return write(writer);
+    }
+
+    writer.write_all(ZSTD_HEADER)?;
+
+    let mut encoder = zstd::Encoder::new(writer, 0)?;
+    write(&mut encoder)?;
+    encoder.finish()?;
+
+    Ok(())
+}
+
+/// Reads the plaintext with the callback, decompressing it if it starts with the header and a
+/// zstd frame.
+///
+/// A plaintext starting with the header without a zstd frame after it is read as is.
+pub(crate) fn read_plaintext<R, F>(reader: &mut R, read: F) -> eyre::Result<()>
+where
+    R: Read,
+    F: FnOnce(&mut dyn Read) -> eyre::Result<()>,
+{
+    let len = ZSTD_HEADER.len() + ZSTD_MAGIC.len();
+
+    let mut prefix = Zeroizing::new(Vec::with_capacity(len));
+    (&mut *reader).take(len as u64).read_to_end(&mut prefix)?;
+
+    if let Some(magic) = prefix.strip_prefix(ZSTD_HEADER)
+        && magic == ZSTD_MAGIC
+    {
+        let mut decoder = zstd::Decoder::new(magic.chain(reader))?;
+
+        return read(&mut decoder);
+    }
+
+    read(&mut prefix.as_slice().chain(reader))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn compressed_plaintext() {
+        let plaintext = "password = hunter2\n".repeat(100);
+
+        let mut compressed = Vec::new();
+        write_plaintext(true, &mut compressed, |writer| {
+            io::copy(&mut plaintext.as_bytes(), writer)?;
+
+            Ok(())
+        })
+        .unwrap();
+        assert!(compressed.starts_with(ZSTD_HEADER));
+        assert!(compressed.len() < plaintext.len());
+
+        let mut out = Vec::new();
+        read_plaintext(&mut compressed.as_slice(), |reader| {
+            io::copy(reader, &mut out)?;
+
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), plaintext);
+
+        // Short and uncompressed plaintexts are copied
+        for plaintext in [
+            "",
+            "mctl",
+            "not compressed",
+            "mctl-zstd/v1\n",
+            "mctl-zstd/v1\nnot compressed",
+        ] {
+            let mut out = Vec::new();
+            read_plaintext(&mut plaintext.as_bytes(), |reader| {
+                io::copy(reader, &mut out)?;
+
+                Ok(())
+            })
+            .unwrap();
+            assert_eq!(out, plaintext.as_bytes());
+        }
+    }
+}
@@ -0,0 +1,224 @@
+//! Generators for new secret values.
+
+use std::fmt::Write;
+
+use age::secrecy::ExposeSecret;
+use base64::{Engine, prelude::BASE64_STANDARD};
+use eyre::{WrapErr, ensure};
+use ssh_key::{Algorithm, LineEnding, PrivateKey, rand_core::OsRng};
+use zeroize::Zeroizing;
+
+use crate::util::{ALPHA_NUM, random_bytes, random_string};
+
+/// Word list used for the passphrases, with 2048 words (11 bits of entropy per word).
+const WORDLIST: &str = include_str!("../assets/bip39-english.txt");
+
+/// Kind of value to generate.
+#[derive(Debug, Clone)]
+pub enum Generator {
+    /// Password with characters from the charset, alphanumeric by default
+    Password {
+        length: usize,
+        charset: Option<String>,
+    },
+    /// Words from the word list separated by dashes
+    Passphrase { words: usize },
+    /// Random bytes encoded as hex
+    Hex { bytes: usize },
+    /// Random bytes encoded as base64
+    Base64 { bytes: usize },
+    /// age X25519 identity
+    X25519,
+    /// OpenSSH ed25519 private key
+    Ed25519,
+    /// Random (version 4) UUID
+    Uuid,
+}
+
+/// Generated secret value.
+pub(crate) struct Generated {
+    pub(crate) value: Zeroizing<String>,
+    /// Public key of the generated key pairs
+    pub(crate) public_key: Option<String>,
+}
+
+impl Generated {
+    fn secret<S>(value: S) -> Self
+    where
+        S: Into<Zeroizing<String>>,
+    {
+        Self {
+            value: value.into(),
+            public_key: None,
+        }
+    }
+}
+
+impl Generator {
+    pub(crate) fn generate(&self) -> eyre::Result<Generated> {
+        match self {
+            Generator::Password { length, charset } => {
+                ensure!(*length > 0, "the password length must be greater than 0");
+
+                let charset = charset
+                    .as_deref()
+                    .unwrap_or(ALPHA_NUM)
+                    .chars()
+                    .collect::<Vec<_>>();
+
+                random_string(&charset, *length).map(Generated::secret)
+            }
+            Generator::Passphrase { words } => {
+                ensure!(*words > 0, "the passphrase must have at least 1 word");
+
+                let wordlist = WORDLIST.lines().collect::<Vec<_>>();
+
+                // The word list length divides 2^16, so the indexes are uniform
+                let indexes = random_bytes(words * 2);
+                let passphrase = indexes
+                    .chunks_exact(2)
+                    .map(|idx| {
+                        let idx = usize::from(u16::from_le_bytes([idx[0], idx[1]]));
+
+                        wordlist[idx % wordlist.len()]
+                    })
+                    .collect::<Vec<_>>()
+                    .join("-");
+
+                Ok(Generated::secret(passphrase))
+            }
+            Generator::Hex { bytes } => {
+                ensure!(*bytes > 0, "the number of bytes must be greater than 0");
+
+                let mut hex = String::with_capacity(bytes * 2);
+                for b in random_bytes(*bytes).iter() {
+                    write!(hex, "{b:02x}")?;
+                }
+
+                Ok(Generated::secret(hex))
+            }
+            Generator::Base64 { bytes } => {
+                ensure!(*bytes > 0, "the number of bytes must be greater than 0");
+
+                Ok(Generated::secret(
+                    BASE64_STANDARD.encode(random_bytes(*bytes).as_slice()),
+                ))
+            }
+            Generator::X25519 => {
+                let identity = age::x25519::Identity::generate();
+                let public_key = identity.to_public().to_string();
+
+                let value = format!(
+                    "# public key: {public_key}\n{}\n",
+                    identity.to_string().expose_secret()
+                );
+
+                Ok(Generated {
+                    value: Zeroizing::new(value),
+                    public_key: Some(public_key),
+                })
+            }
+            Generator::Ed25519 => {
+                let key = PrivateKey::random(&mut OsRng, Algorithm::Ed25519)
+                    .wrap_err("couldn't generate the ed25519 key")?;
+
+                let public_key = key
+                    .public_key()
+                    .to_openssh()
+                    .wrap_err("couldn't encode the ed25519 public key")?;
+                let value = key
+                    .to_openssh(LineEnding::LF)
+                    .wrap_err("couldn't encode the ed25519 private key")?;
+
+                Ok(Generated {
+                    value: Zeroizing::new(value.to_string()),
+                    public_key: Some(public_key),
+                })
+            }
+            Generator::Uuid => {
+                let mut bytes = random_bytes(16);
+
+                // Version 4 and RFC 4122 variant
+                bytes[6] = (bytes[6] & 0x0f) | 0x40;
+                bytes[8] = (bytes[8] & 0x3f) | 0x80;
+
+                let mut uuid = String::with_capacity(36);
+                for (i, b) in bytes.iter().enumerate() {
+                    if matches!(i, 4 | 6 | 8 | 10) {
+                        uuid.push('-');
+                    }
+
+                    write!(uuid, "{b:02x}")?;
+                }
+
+                Ok(Generated::secret(uuid))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    #[test]
+    fn generate_values() {
+        let password = Generator::Password {
+            length: 20,
+            charset: Some("ab".to_string()),
+        }
+        .generate()
+        .unwrap();
+        assert_eq!(password.value.len(), 20);
+        assert!(password.value.chars().all(|c| c == 'a' || c == 'b'));
+        assert!(password.public_key.is_none());
+
+        let passphrase = Generator::Passphrase { words: 6 }.generate().unwrap();
+        assert_eq!(passphrase.value.split('-').count(), 6);
+        assert!(
+            passphrase
+                .value
+                .split('-')
+                .all(|word| WORDLIST.lines().any(|w| w == word))
+        );
+
+        let hex = Generator::Hex { bytes: 16 }.generate().unwrap();
+        assert_eq!(hex.value.len(), 32);
+        assert!(hex.value.chars().all(|c| c.is_ascii_hexdigit()));
+
+        let base64 = Generator::Base64 { bytes: 32 }.generate().unwrap();
+        assert_eq!(
+            BASE64_STANDARD.decode(base64.value.as_str()).unwrap().len(),
+            32
+        );
+
+        let uuid = Generator::Uuid.generate().unwrap();
+        let groups = uuid.value.split('-').map(str::len).collect::<Vec<_>>();
+        assert_eq!(groups, [8, 4, 4, 4, 12]);
+        assert_eq!(uuid.value.as_bytes()[14], b'4');
+
+        let empty = Generator::Password {
+            length: 8,
+            charset: Some(String::new()),
+        };
+        assert!(empty.generate().is_err());
+    }
+
+    #[test]
+    fn generate_key_pairs() {
+        let x25519 = Generator::X25519.generate().unwrap();
+        let key_line = x25519.value.lines().last().unwrap();
+        let identity = age::x25519::Identity::from_str(key_line).unwrap();
+        assert_eq!(x25519.public_key.unwrap(), identity.to_public().to_string());
+
+        let ed25519 = Generator::Ed25519.generate().unwrap();
+        let key = PrivateKey::from_openssh(ed25519.value.as_bytes()).unwrap();
+        assert_eq!(key.algorithm(), Algorithm::Ed25519);
+        assert_eq!(
+            ed25519.public_key.unwrap(),
+            key.public_key().to_openssh().unwrap()
+        );
+    }
+}
@@ -0,0 +1,341 @@
+//! Integration with git to diff and merge the encrypted secrets.
+
+use std::{
+    fs,
+    io::{Write, stdout},
+    path::Path,
+    process::Command,
+};
+
+use color_eyre::{Section, owo_colors::OwoColorize};
+use diffy::MergeOptions;
+use eyre::{WrapErr, bail, eyre};
+use tracing::{debug, info, warn};
+use zeroize::Zeroizing;
+
+use crate::config::{Config, RecipientKey};
+use crate::encoding::Encoding;
+use crate::manifest::Manifest;
+use crate::rules;
+use crate::secret::{decrypt, encrypt_with};
+use crate::util::write_atomic;
+
+/// Name of the diff and merge drivers in the git configuration.
+const DRIVER: &str = "mctl";
+
+/// Decrypts a secret to stdout, for the `diff.mctl.textconv` git option.
+pub fn textconv(file: &Path) -> eyre::Result<()> {
+    let config = crate::config();
+
+    let content = decrypt_file(config, file)?;
+
+    stdout().write_all(&content)?;
+
+    Ok(())
+}
+
+/// Merges three versions of a secret, for the `merge.mctl.driver` git option.
+///
+/// The result is written encrypted to the current version. The path of the merged secret is
+/// used to choose the recipients, it defaults to the current version.
+pub fn merge_driver(
+    base: &Path,
+    current: &Path,
+    other: &Path,
+    path: Option<&Path>,
+    marker_size: usize,
+) -> eyre::Result<()> {
+    let config = crate::config();
+
+    let path = path.unwrap_or(current);
+    let recipients = rules::recipients_for(config, path)?;
+
+    let encoding = rules::encoding_for(path)?;
+
+    let clean = merge_secrets(
+        config,
+        [base, current, other],
+        &recipients,
+        encoding,
+        marker_size,
+    )?;
+
+    if path != current {
+        Manifest::record(config, path, &recipients)?;
+    }
+
+    if !clean {
+        return Err(eyre!("merge conflicts in {}", path.display())).note(format!(
+            "resolve them with {}",
+            format!("mctl secret edit {}", path.display()).blue()
+        ));
+    }
+
+    info!(path = %path.display(), "secret merged");
+
+    Ok(())
+}
+
+/// Three-way merge of the decrypted secrets, returns false if there are conflicts.
+///
+/// The conflict markers are left in the encrypted result.
+fn merge_secrets(
+    config: &Config,
+    [base, current, other]: [&Path; 3],
+    recipients: &[RecipientKey],
+    encoding: Encoding,
+    marker_size: usize,
+) -> eyre::Result<bool> {
+    let base_content = decrypt_file(config, base).wrap_err("couldn't decrypt the base")?;
+    let current_content =
+        decrypt_file(config, current).wrap_err("couldn't decrypt the current version")?;
+    let other_content =
+        decrypt_file(config, other).wrap_err("couldn't decrypt the other version")?;
+
+    let (merged, clean) = match MergeOptions::new()
+        .set_conflict_marker_length(marker_size)
+        .merge_bytes(&base_content, &current_content, &other_content)
+    {
+        Ok(merged) => (Zeroizing::new(merged), true),
+        Err(conflicts) => (Zeroizing::new(conflicts), false),
+    };
+
+    debug!(clean, "merged secrets");
+
+    write_atomic(current, |file| {
+        encrypt_with(recipients, encoding, &mut merged.as_slice(), file)
+    })?;
+
+    Ok(clean)
+}
+
+/// Decrypts a secret in memory, empty files are empty secrets.
+///
+/// Git passes an empty file when a version doesn't exist, like a base for files added in both
+/// branches.
+fn decrypt_file(config: &Config, file: &Path) -> eyre::Result<Zeroizing<Vec<u8>>> {
+    let raw = fs::read(file).wrap_err_with(|| format!("couldn't read {}", file.display()))?;
+
+    let mut content = Zeroizing::new(Vec::new());
+
+    if raw.is_empty() {
+        return Ok(content);
+    }
+
+    decrypt(config, &mut raw.as_slice(), &mut *content)?;
+
+    Ok(content)
+}
+
+/// Configures the diff and merge drivers in the repository of the current directory.
+pub fn setup<S>(patterns: &[S]) -> eyre::Result<()>
+where
+    S: AsRef<str>,
+{
+    let root = git(&["rev-parse", "--show-toplevel"])?;
+    let root = Path::new(root.trim());
+
+    let attributes = root.join(".gitattributes");
+
+    let mut content = match fs::read_to_string(&attributes) {
+        Ok(content) => content,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(err) => return Err(err).wrap_err("couldn't read .gitattributes"),
+    };
+
+    let mut changed = false;
+
+    for pattern in patterns {
+        let pattern = pattern.as_ref();
+        let line = format!("{pattern} diff={DRIVER} merge={DRIVER}");
+
+        if content.lines().any(|l| l.trim() == line) {
+            info!(pattern, "the .gitattributes already uses the mctl drivers");
+
+            continue;
+        }
+
+        if content
+            .lines()
+            .any(|l| l.split_whitespace().next() == Some(pattern))
+        {
+            warn!(
+                pattern,
+                "the .gitattributes already has attributes for the pattern"
+            );
+        }
+
+        if !content.is_empty() && !content.ends_with('\n') {
+            content.push('\n');
+        }
+        content.push_str(&line);
+        content.push('\n');
+
+        changed = true;
+    }
+
+    if changed {
+        fs::write(&attributes, content).wrap_err("couldn't write .gitattributes")?;
+
+        info!(path = %attributes.display(), "added the mctl drivers");
+    }
+
+    // The textconv output is not cached, since it would store the plaintext in the repository
+    let options = [
+        (format!("diff.{DRIVER}.textconv"), "mctl git textconv"),
+        (format!("diff.{DRIVER}.cachetextconv"), "false"),
+        (format!("merge.{DRIVER}.name"), "mctl encrypted secrets"),
+        (
+            format!("merge.{DRIVER}.driver"),
+            "mctl git merge-driver --marker-size %L %O %A %B %P",
+        ),
+    ];
+
+    for (key, value) in &options {
+        git(&["config", "--local", key, value])?;
+
+        debug!(key, value, "set git option");
+    }
+
+    info!("configured the git drivers");
+
+    Ok(())
+}
+
+/// Reads the content of a file at a git revision.
+pub(crate) fn show(rev: &str, file: &Path) -> eyre::Result<Vec<u8>> {
+    let dir = match file.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let name = file
+        .file_name()
+        .ok_or_else(|| eyre!("invalid secret path: {}", file.display()))?;
+
+    // The ./ prefix makes the path relative to the working directory of the command
+    let object = format!("{rev}:./{}", name.to_string_lossy());
+
+    let output = Command::new("git")
+        .args(["show", &object])
+        .current_dir(dir)
+        .output()
+        .wrap_err("couldn't run git")?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+
+        bail!("git show {object} failed: {}", stderr.trim());
+    }
+
+    Ok(output.stdout)
+}
+
+fn git(args: &[&str]) -> eyre::Result<String> {
+    let output = Command::new("git")
+        .args(args)
+        .output()
+        .wrap_err("couldn't run git")?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+
+        bail!("git {} failed: {}", args.join(" "), stderr.trim());
+    }
+
+    String::from_utf8(output.stdout).wrap_err("git output is not valid UTF-8")
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::secret::encrypt;
+
+    fn write_secret(config: &Config, path: &Path, content: &str) {
+        let recipients = config.secrets.recipients().unwrap();
+
+        write_atomic(path, |file| {
+            encrypt(&recipients, &mut content.as_bytes(), file)
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn merge_encrypted_secrets() {
+        let tmp = TempDir::new().unwrap();
+
+        let config = Config::mock();
+        let recipients = config.secrets.recipients().unwrap();
+
+        let base = tmp.path().join("base.pem");
+        let current = tmp.path().join("current.pem");
+        let other = tmp.path().join("other.pem");
+
+        write_secret(&config, &base, "user = admin\npassword = old\nport = 1\n");
+        write_secret(&config, &current, "user = root\npassword = old\nport = 1\n");
+        write_secret(&config, &other, "user = admin\npassword = old\nport = 2\n");
+
+        let clean = merge_secrets(
+            &config,
+            [&base, &current, &other],
+            &recipients,
+            Encoding::default(),
+            7,
+        )
+        .unwrap();
+        assert!(clean);
+
+        let merged = decrypt_file(&config, &current).unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&merged),
+            "user = root\npassword = old\nport = 2\n"
+        );
+
+        write_secret(
+            &config,
+            &current,
+            "user = root\npassword = mine\nport = 1\n",
+        );
+        write_secret(
+            &config,
+            &other,
+            "user = root\npassword = theirs\nport = 1\n",
+        );
+
+        let clean = merge_secrets(
+            &config,
+            [&base, &current, &other],
+            &recipients,
+            Encoding::default(),
+            7,
+        )
+        .unwrap();
+        assert!(!clean);
+
+        let merged = decrypt_file(&config, &current).unwrap();
+        let merged = String::from_utf8_lossy(&merged);
+        assert_eq!(
+            merged,
+            "<<<<<<< ours\nuser = root\npassword = mine\n||||||| original\nuser = admin\n\
+             password = old\n=======\nuser = root\npassword = theirs\n>>>>>>> theirs\nport = 1\n"
+        );
+
+        // Missing base for files added in both branches
+        let empty = tmp.path().join("empty");
+        fs::write(&empty, "").unwrap();
+
+        write_secret(&config, &current, "same\n");
+        write_secret(&config, &other, "same\n");
+
+        let clean = merge_secrets(
+            &config,
+            [&empty, &current, &other],
+            &recipients,
+            Encoding::default(),
+            7,
+        )
+        .unwrap();
+        assert!(clean);
+    }
+}
@@ -0,0 +1,86 @@
+use std::io::Read;
+
+use age::armor::ArmoredReader;
+use age_core::format::{Stanza, read::legacy_age_stanza};
+use base64::{Engine, prelude::BASE64_STANDARD_NO_PAD};
+use eyre::{OptionExt, WrapErr, ensure, eyre};
+use sha2::{Digest, Sha256};
+
+const VERSION_LINE: &[u8] = b"age-encryption.org/v1\n";
+const MAC_PREFIX: &[u8] = b"---";
+const ARMOR_BEGIN: &[u8] = b"-----BEGIN AGE ENCRYPTED FILE-----";
+
+/// Header of an age file, read without decrypting it.
+pub(crate) struct Header {
+    pub(crate) armored: bool,
+    pub(crate) stanzas: Vec<Stanza>,
+    /// Size of the encrypted payload after the header, in bytes
+    pub(crate) payload_len: usize,
+}
+
+impl Header {
+    pub(crate) fn read(raw: &[u8]) -> eyre::Result<Self> {
+        let armored = raw.trim_ascii_start().starts_with(ARMOR_BEGIN);
+
+        let mut content = Vec::new();
+        ArmoredReader::new(raw)
+            .read_to_end(&mut content)
+            .wrap_err("couldn't read the age file")?;
+
+        let (stanzas, header_len) = Self::parse(&content)?;
+
+        Ok(Self {
+            armored,
+            stanzas,
+            payload_len: content.len() - header_len,
+        })
+    }
+
+    /// Parses the stanzas in the header, returning them with the header length.
+    fn parse(content: &[u8]) -> eyre::Result<(Vec<Stanza>, usize)> {
+        let mut rest = content
+            .strip_prefix(VERSION_LINE)
+            .ok_or_eyre("not an age v1 file")?;
+
+        let mut stanzas = Vec::new();
+
+        while !rest.starts_with(MAC_PREFIX) {
+            let (next, stanza) =
+                legacy_age_stanza(rest).map_err(|_| eyre!("invalid recipient stanza"))?;
+
+            stanzas.push(Stanza::from(stanza));
+
+            rest = next;
+        }
+
+        let mac_end = rest
+            .iter()
+            .position(|b| *b == b'\n')
+            .ok_or_eyre("missing header MAC")?;
+
+        let header_len = content.len() - rest.len() + mac_end + 1;
+
+        ensure!(!stanzas.is_empty(), "the header has no recipient stanzas");
+
+        Ok((stanzas, header_len))
+    }
+}
+
+/// Returns the tag of an SSH recipient, as it appears in the stanzas arguments.
+///
+/// It's the first 4 bytes of the SHA-256 of the public key in the SSH wire format.
+pub(crate) fn ssh_recipient_tag(recipient: &str) -> Option<String> {
+    let mut parts = recipient.split_whitespace();
+
+    let key_type = parts.next()?;
+    if !key_type.starts_with("ssh-") {
+        return None;
+    }
+
+    let key = base64::prelude::BASE64_STANDARD
+        .decode(parts.next()?)
+        .ok()?;
+    let digest = Sha256::digest(&key);
+
+    Some(BASE64_STANDARD_NO_PAD.encode(&digest[..4]))
+}
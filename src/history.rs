@@ -0,0 +1,213 @@
+//! Previous versions of the secrets, kept locally to undo an edit.
+
+use std::{
+    cmp::Reverse,
+    collections::BTreeSet,
+    fs::{self, DirBuilder},
+    io::{self, Write},
+    os::unix::{ffi::OsStrExt, fs::DirBuilderExt},
+    path::{Path, PathBuf},
+};
+
+use eyre::{WrapErr, eyre};
+use jiff::{SpanRelativeTo, Timestamp};
+use tracing::{debug, warn};
+
+use crate::{
+    config::Config,
+    manifest::{Manifest, split_secret_path},
+    rules::absolute_secret_path,
+    util::create_private_file,
+};
+
+/// File with the path of the secret, in its versions directory.
+const PATH_FILE: &str = "path";
+
+/// Previous version of a secret, the most recent is the number 1.
+#[derive(Debug)]
+pub(crate) struct Version {
+    pub(crate) number: usize,
+    pub(crate) timestamp: Timestamp,
+    /// Truncated hash of the ciphertext
+    pub(crate) hash: String,
+    path: PathBuf,
+}
+
+impl Version {
+    /// Returns the ciphertext of the version.
+    pub(crate) fn content(&self) -> eyre::Result<Vec<u8>> {
+        fs::read(&self.path)
+            .wrap_err_with(|| format!("couldn't read version: {}", self.path.display()))
+    }
+
+    /// Returns the fingerprints of the recipients recorded when the version was saved.
+    pub(crate) fn fingerprints(&self) -> eyre::Result<Option<BTreeSet<String>>> {
+        match fs::read_to_string(self.path.with_extension("recipients")) {
+            Ok(content) => Ok(Some(
+                content
+                    .trim()
+                    .split(',')
+                    .filter(|fp| !fp.is_empty())
+                    .map(str::to_string)
+                    .collect(),
+            )),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err).wrap_err("couldn't read the version recipients"),
+        }
+    }
+
+    fn remove(&self) -> eyre::Result<()> {
+        debug!(path = %self.path.display(), "removing version");
+
+        fs::remove_file(&self.path)?;
+
+        match fs::remove_file(self.path.with_extension("recipients")) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Directory with the versions of a secret, named after the hash of its absolute path.
+fn versions_dir(config: &Config, secret: &Path) -> eyre::Result<PathBuf> {
+    let secret = absolute_secret_path(secret)?;
+
+    let hash = blake3::hash(secret.as_os_str().as_bytes()).to_hex();
+
+    Ok(config.dirs.history().join(&hash[..16]))
+}
+
+/// Saves the current ciphertext of a secret, before it's overwritten.
+pub(crate) fn save(config: &Config, secret: &Path) -> eyre::Result<()> {
+    if config.history.keep == 0 {
+        return Ok(());
+    }
+
+    let content = match fs::read(secret) {
+        Ok(content) => content,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err).wrap_err("couldn't read secret file"),
+    };
+
+    let dir = versions_dir(config, secret)?;
+
+    DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(&dir)
+        .wrap_err_with(|| format!("couldn't create history directory: {}", dir.display()))?;
+
+    let hash = blake3::hash(&content).to_hex()[..16].to_string();
+
+    if list(&dir)?
+        .first()
+        .is_some_and(|version| version.hash == hash)
+    {
+        debug!(hash, "version already saved");
+
+        return Ok(());
+    }
+
+    let name = format!("{}-{hash}", Timestamp::now().as_millisecond());
+    let path = dir.join(&name).with_extension("pem");
+
+    debug!(path = %path.display(), "saving previous version");
+
+    let mut file = create_private_file(&path)?;
+    file.write_all(&content)?;
+    file.sync_all()?;
+
+    let (secret_dir, _) = split_secret_path(secret)?;
+    if let Some(fingerprints) = Manifest::read(secret_dir)?.recipients(secret) {
+        let fingerprints = fingerprints.iter().cloned().collect::<Vec<_>>().join(",");
+
+        fs::write(path.with_extension("recipients"), fingerprints)?;
+    }
+
+    let path_file = dir.join(PATH_FILE);
+    if !path_file.exists() {
+        fs::write(
+            &path_file,
+            absolute_secret_path(secret)?.as_os_str().as_bytes(),
+        )?;
+    }
+
+    prune(config, &dir)
+}
+
+/// Returns the saved versions of a secret, from the most recent.
+pub(crate) fn versions(config: &Config, secret: &Path) -> eyre::Result<Vec<Version>> {
+    let dir = versions_dir(config, secret)?;
+
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    list(&dir)
+}
+
+fn list(dir: &Path) -> eyre::Result<Vec<Version>> {
+    let mut versions = Vec::new();
+
+    for entry in fs::read_dir(dir).wrap_err("couldn't read history directory")? {
+        let path = entry?.path();
+
+        if path.extension().is_none_or(|ext| ext != "pem") {
+            continue;
+        }
+
+        let Some((timestamp, hash)) = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| stem.split_once('-'))
+        else {
+            warn!(path = %path.display(), "invalid version file name");
+
+            continue;
+        };
+
+        let timestamp = timestamp
+            .parse::<i64>()
+            .ok()
+            .and_then(|ms| Timestamp::from_millisecond(ms).ok())
+            .ok_or_else(|| eyre!("invalid version timestamp: {}", path.display()))?;
+
+        versions.push(Version {
+            number: 0,
+            timestamp,
+            hash: hash.to_string(),
+            path,
+        });
+    }
+
+    versions.sort_unstable_by_key(|version| Reverse(version.timestamp));
+
+    for (idx, version) in versions.iter_mut().enumerate() {
+        version.number = idx + 1;
+    }
+
+    Ok(versions)
+}
+
+/// Removes the versions over the retention count or older than the maximum age.
+fn prune(config: &Config, dir: &Path) -> eyre::Result<()> {
+    let max_age = config
+        .history
+        .max_age
+        .map(|span| span.to_duration(SpanRelativeTo::days_are_24_hours()))
+        .transpose()
+        .wrap_err("invalid history max age")?;
+
+    let now = Timestamp::now();
+
+    for version in list(dir)? {
+        let expired =
+            max_age.is_some_and(|max_age| now.duration_since(version.timestamp) > max_age);
+
+        if version.number > config.history.keep || expired {
+            version.remove()?;
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,325 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{Cursor, Read},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Mutex,
+};
+
+use age::{DecryptError, Decryptor, Identity, armor::ArmoredReader, secrecy::SecretString};
+use age_core::format::{FileKey, Stanza};
+use eyre::{WrapErr, ensure, eyre};
+use tracing::{debug, error};
+use zeroize::Zeroizing;
+
+use crate::config::Passphrase;
+
+/// Content of the passphrase protected identity files already unlocked by the process.
+static UNLOCKED_AGE: Mutex<BTreeMap<PathBuf, Zeroizing<String>>> = Mutex::new(BTreeMap::new());
+
+/// SSH keys already unlocked by the process.
+static UNLOCKED_SSH: Mutex<BTreeMap<PathBuf, age::ssh::Identity>> = Mutex::new(BTreeMap::new());
+
+/// Reads all the identities in a key file.
+///
+/// The file can contain age X25519 keys, one per line, an OpenSSH private key, or be an age
+/// file encrypted with a passphrase containing the X25519 keys.
+pub(crate) fn read_identity_file(
+    key_file: &Path,
+    passphrase: &Passphrase,
+) -> eyre::Result<Vec<Box<dyn Identity>>> {
+    debug!(file = %key_file.display(), "reading identity file");
+
+    let content = Zeroizing::new(fs::read(key_file).wrap_err("couldn't read identity file")?);
+
+    if is_age_encrypted(&content) {
+        let content = unlock_identity_file(key_file, &content, passphrase)?;
+
+        return parse_x25519_identities(&content);
+    }
+
+    let content = str::from_utf8(&content).wrap_err("identity file is not valid UTF-8")?;
+
+    if is_ssh_key(content) {
+        return read_ssh_identity(key_file, content, passphrase).map(|identity| vec![identity]);
+    }
+
+    parse_x25519_identities(content)
+}
+
+/// Unlocks a passphrase protected SSH key now, instead of when it's first needed.
+pub(crate) fn unlock_ssh_key(key_file: &Path, passphrase: &Passphrase) -> eyre::Result<()> {
+    let content = Zeroizing::new(fs::read(key_file).wrap_err("couldn't read identity file")?);
+
+    let Ok(content) = str::from_utf8(&content) else {
+        return Ok(());
+    };
+
+    if !is_ssh_key(content) {
+        return Ok(());
+    }
+
+    let identity = age::ssh::Identity::from_buffer(
+        Cursor::new(content.as_bytes()),
+        Some(key_file.display().to_string()),
+    )
+    .wrap_err("couldn't parse SSH identity")?;
+
+    let age::ssh::Identity::Encrypted(key) = identity else {
+        return Ok(());
+    };
+
+    let identity = EncryptedSshIdentity {
+        key,
+        key_file: key_file.to_path_buf(),
+        passphrase: passphrase.clone(),
+    };
+
+    match identity.unlock() {
+        Some(Ok(_)) => Ok(()),
+        Some(Err(err)) => Err(err).wrap_err("couldn't unlock the SSH key"),
+        None => Err(eyre!("couldn't read the passphrase of the SSH key")),
+    }
+}
+
+/// Checks that the key file can be read, without unlocking it.
+pub(crate) fn check_identity_file(key_file: &Path, passphrase: &Passphrase) -> eyre::Result<()> {
+    let content = Zeroizing::new(fs::read(key_file).wrap_err("couldn't read identity file")?);
+
+    if !is_age_encrypted(&content) {
+        return read_identity_file(key_file, passphrase).map(drop);
+    }
+
+    let decryptor = Decryptor::new(ArmoredReader::new(content.as_slice()))
+        .wrap_err("couldn't parse encrypted identity file")?;
+
+    ensure!(
+        decryptor.is_scrypt(),
+        "encrypted identity file must be protected by a passphrase"
+    );
+
+    Ok(())
+}
+
+fn is_age_encrypted(content: &[u8]) -> bool {
+    content.starts_with(b"age-encryption.org/")
+        || content
+            .trim_ascii_start()
+            .starts_with(b"-----BEGIN AGE ENCRYPTED FILE-----")
+}
+
+fn is_ssh_key(content: &str) -> bool {
+    content.trim_start().starts_with("-----BEGIN")
+}
+
+fn parse_x25519_identities(content: &str) -> eyre::Result<Vec<Box<dyn Identity>>> {
+    let identities = parse_x25519_keys(content)?
+        .into_iter()
+        .map(|identity| Box::new(identity) as Box<dyn Identity>)
+        .collect();
+
+    Ok(identities)
+}
+
+fn parse_x25519_keys(content: &str) -> eyre::Result<Vec<age::x25519::Identity>> {
+    let identities = content
+        .lines()
+        .enumerate()
+        .filter(|(_, l)| !(l.starts_with("#") || l.is_empty()))
+        .map(|(n, l)| {
+            age::x25519::Identity::from_str(l).map_err(|err| {
+                eyre!("{err}").wrap_err(format!("couldn't parse age identity on line {}", n + 1))
+            })
+        })
+        .collect::<eyre::Result<Vec<_>>>()?;
+
+    ensure!(!identities.is_empty(), "couldn't find key line in key file");
+
+    Ok(identities)
+}
+
+/// Returns the public keys of the identities in a key file.
+///
+/// The public key of an SSH key is read without unlocking it, a passphrase protected identity
+/// file is unlocked if it wasn't already.
+pub(crate) fn read_public_keys(
+    key_file: &Path,
+    passphrase: &Passphrase,
+) -> eyre::Result<Vec<String>> {
+    let content = Zeroizing::new(fs::read(key_file).wrap_err("couldn't read identity file")?);
+
+    if is_age_encrypted(&content) {
+        let content = unlock_identity_file(key_file, &content, passphrase)?;
+
+        return x25519_public_keys(&content);
+    }
+
+    let content = str::from_utf8(&content).wrap_err("identity file is not valid UTF-8")?;
+
+    if is_ssh_key(content) {
+        let public_key = ssh_key::PrivateKey::from_openssh(content)
+            .wrap_err("couldn't parse SSH identity")?
+            .public_key()
+            .to_openssh()
+            .wrap_err("couldn't encode the SSH public key")?;
+
+        return Ok(vec![public_key]);
+    }
+
+    x25519_public_keys(content)
+}
+
+fn x25519_public_keys(content: &str) -> eyre::Result<Vec<String>> {
+    let public_keys = parse_x25519_keys(content)?
+        .iter()
+        .map(|identity| identity.to_public().to_string())
+        .collect();
+
+    Ok(public_keys)
+}
+
+/// Decrypts an identity file protected by a passphrase.
+///
+/// The decrypted content is kept in memory, so the passphrase is requested only once.
+fn unlock_identity_file(
+    key_file: &Path,
+    content: &[u8],
+    passphrase: &Passphrase,
+) -> eyre::Result<Zeroizing<String>> {
+    let mut unlocked = UNLOCKED_AGE
+        .lock()
+        .map_err(|_| eyre!("unlocked identities lock poisoned"))?;
+
+    if let Some(content) = unlocked.get(key_file) {
+        return Ok(content.clone());
+    }
+
+    debug!(file = %key_file.display(), "unlocking identity file");
+
+    let decryptor = Decryptor::new(ArmoredReader::new(content))
+        .wrap_err("couldn't parse encrypted identity file")?;
+
+    ensure!(
+        decryptor.is_scrypt(),
+        "encrypted identity file must be protected by a passphrase"
+    );
+
+    let passphrase = passphrase.request(
+        key_file,
+        &format!(
+            "Enter passphrase for identity file {}: ",
+            key_file.display()
+        ),
+    )?;
+    let identity = age::scrypt::Identity::new(passphrase);
+
+    let mut decrypted = Zeroizing::new(String::new());
+    decryptor
+        .decrypt(std::iter::once(&identity as &dyn Identity))
+        .wrap_err("couldn't unlock the identity file")?
+        .read_to_string(&mut decrypted)
+        .wrap_err("couldn't read the decrypted identity file")?;
+
+    unlocked.insert(key_file.to_path_buf(), decrypted.clone());
+
+    Ok(decrypted)
+}
+
+fn read_ssh_identity(
+    key_file: &Path,
+    content: &str,
+    passphrase: &Passphrase,
+) -> eyre::Result<Box<dyn Identity>> {
+    debug!("reading SSH identity");
+
+    let identity = age::ssh::Identity::from_buffer(
+        Cursor::new(content.as_bytes()),
+        Some(key_file.display().to_string()),
+    )
+    .wrap_err("couldn't parse SSH identity")?;
+
+    match identity {
+        age::ssh::Identity::Unencrypted(_) => Ok(Box::new(identity)),
+        age::ssh::Identity::Encrypted(key) => Ok(Box::new(EncryptedSshIdentity {
+            key,
+            key_file: key_file.to_path_buf(),
+            passphrase: passphrase.clone(),
+        })),
+        age::ssh::Identity::Unsupported(key) => {
+            Err(eyre!("{}", UnsupportedDisplay(&key, key_file)))
+        }
+    }
+}
+
+/// Uses the same message as age to explain why the key is not supported.
+struct UnsupportedDisplay<'a>(&'a age::ssh::UnsupportedKey, &'a Path);
+
+impl std::fmt::Display for UnsupportedDisplay<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.display(f, Some(&self.1.display().to_string()))
+    }
+}
+
+/// SSH key protected by a passphrase.
+///
+/// The passphrase is requested only the first time the key is needed.
+struct EncryptedSshIdentity {
+    key: age::ssh::EncryptedKey,
+    key_file: PathBuf,
+    passphrase: Passphrase,
+}
+
+impl EncryptedSshIdentity {
+    fn unlock(&self) -> Option<Result<age::ssh::Identity, DecryptError>> {
+        let mut unlocked = UNLOCKED_SSH.lock().ok()?;
+
+        if let Some(identity) = unlocked.get(&self.key_file) {
+            return Some(Ok(identity.clone()));
+        }
+
+        let passphrase = match self.passphrase.request(
+            &self.key_file,
+            &format!("Enter passphrase for SSH key {}: ", self.key_file.display()),
+        ) {
+            Ok(passphrase) => passphrase,
+            Err(err) => {
+                error!(error = format!("{err:#}"), "couldn't read the passphrase");
+
+                return None;
+            }
+        };
+
+        let identity = match self.key.decrypt(passphrase) {
+            Ok(key) => age::ssh::Identity::from(key),
+            Err(err) => return Some(Err(err)),
+        };
+
+        unlocked.insert(self.key_file.clone(), identity.clone());
+
+        Some(Ok(identity))
+    }
+}
+
+impl Identity for EncryptedSshIdentity {
+    fn unwrap_stanza(&self, stanza: &Stanza) -> Option<Result<FileKey, DecryptError>> {
+        // Only prompt for the passphrase if the stanza could be for an SSH key
+        if !stanza.tag.starts_with("ssh-") {
+            return None;
+        }
+
+        match self.unlock()? {
+            Ok(identity) => identity.unwrap_stanza(stanza),
+            Err(err) => Some(Err(err)),
+        }
+    }
+}
+
+/// Reads a passphrase from the terminal.
+pub(crate) fn prompt_passphrase(prompt: &str) -> eyre::Result<SecretString> {
+    let passphrase = Zeroizing::new(
+        rpassword::prompt_password(prompt).wrap_err("couldn't read passphrase from the TTY")?,
+    );
+
+    Ok(SecretString::from(passphrase.as_str()))
+}
@@ -2,8 +2,24 @@ use std::sync::OnceLock;
 
 use self::config::Config;
 
+pub mod agent;
+pub(crate) mod archive;
 pub mod config;
+pub(crate) mod document;
+pub(crate) mod encoding;
+pub mod generate;
+pub mod git;
+pub(crate) mod header;
+pub(crate) mod history;
+pub(crate) mod identity;
+pub(crate) mod lock;
+pub(crate) mod manifest;
+pub(crate) mod recovery;
+pub(crate) mod rules;
 pub mod secret;
+pub mod store;
+pub(crate) mod temp;
+pub(crate) mod template;
 pub(crate) mod util;
 
 pub static CONFIG: OnceLock<Config> = OnceLock::new();
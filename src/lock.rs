@@ -0,0 +1,172 @@
+//! Advisory locks to serialize the changes to a secret, or to the manifest of a directory,
+//! between processes, and to mark the temporary files still in use.
+
+use std::{
+    fs::{self, DirBuilder, File, TryLockError},
+    io,
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{DirBuilderExt, MetadataExt},
+    },
+    path::{Path, PathBuf},
+};
+
+use color_eyre::Section;
+use eyre::{OptionExt, WrapErr, eyre};
+use tracing::{debug, warn};
+
+use crate::{config::Config, rules::absolute_secret_path, util::create_private_file};
+
+/// Exclusive lock on a secret or a shared file, released when dropped.
+///
+/// The lock files are in the cache directory, named after the hash of the absolute path of the
+/// secret, so they don't end up next to the secrets. They are removed when released, so they
+/// don't pile up for every secret ever edited.
+#[derive(Debug)]
+pub(crate) struct SecretLock {
+    path: PathBuf,
+    // Keeps the lock
+    _file: File,
+}
+
+impl SecretLock {
+    /// Takes the lock on the secret, failing if another process holds it.
+    pub(crate) fn acquire(config: &Config, secret: &Path) -> eyre::Result<Self> {
+        let path = lock_path(config, &absolute_secret_path(secret)?)?;
+
+        match Self::try_lock(path)? {
+            Some(lock) => Ok(lock),
+            None => Err(eyre!("the secret is locked by another process"))
+                .with_note(|| format!("secret: {}", secret.display()))
+                .note("wait for the other edit to finish"),
+        }
+    }
+
+    /// Takes the lock on the manifest of a directory, waiting for the other processes to
+    /// release it.
+    pub(crate) fn wait_manifest(config: &Config, dir: &Path) -> eyre::Result<Self> {
+        let absolute = fs::canonicalize(dir)
+            .wrap_err_with(|| format!("couldn't resolve directory: {}", dir.display()))?;
+
+        let path = lock_path(config, &absolute)?;
+
+        Self::lock_file(path, true)?.ok_or_eyre("couldn't lock the manifest")
+    }
+
+    /// Locks the file, returning [`None`] if another process holds it and not waiting.
+    pub(crate) fn try_lock(path: PathBuf) -> eyre::Result<Option<Self>> {
+        Self::lock_file(path, false)
+    }
+
+    fn lock_file(path: PathBuf, wait: bool) -> eyre::Result<Option<Self>> {
+        loop {
+            // Truncating is fine, the content is not used
+            let file = create_private_file(&path)?;
+
+            if wait {
+                file.lock()
+                    .wrap_err_with(|| format!("couldn't lock {}", path.display()))?;
+            } else {
+                match file.try_lock() {
+                    Ok(()) => {}
+                    Err(TryLockError::WouldBlock) => return Ok(None),
+                    Err(TryLockError::Error(err)) => {
+                        return Err(err)
+                            .wrap_err_with(|| format!("couldn't lock {}", path.display()));
+                    }
+                }
+            }
+
+            // The previous holder may have removed the file after we opened it
+            if is_same_file(&file, &path)? {
+                debug!(path = %path.display(), "locked file");
+
+                return Ok(Some(Self { path, _file: file }));
+            }
+        }
+    }
+}
+
+/// Returns the lock file of an absolute path in the cache, creating the locks directory.
+fn lock_path(config: &Config, absolute: &Path) -> eyre::Result<PathBuf> {
+    let dir = config.dirs.cache()?.join("locks");
+
+    DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(&dir)
+        .wrap_err_with(|| format!("couldn't create locks directory: {}", dir.display()))?;
+
+    let hash = blake3::hash(absolute.as_os_str().as_bytes()).to_hex();
+
+    Ok(dir.join(format!("{}.lock", &hash[..16])))
+}
+
+/// Checks the path still refers to the opened file.
+fn is_same_file(file: &File, path: &Path) -> eyre::Result<bool> {
+    let opened = file.metadata()?;
+
+    match fs::metadata(path) {
+        Ok(current) => Ok(current.dev() == opened.dev() && current.ino() == opened.ino()),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(err) => Err(err).wrap_err("couldn't read the lock file"),
+    }
+}
+
+impl Drop for SecretLock {
+    fn drop(&mut self) {
+        // Removed while still locked, the file is released after
+        if let Err(err) = fs::remove_file(&self.path) {
+            warn!(error = %err, path = %self.path.display(), "couldn't remove lock file");
+        }
+
+        debug!(path = %self.path.display(), "unlocked file");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn remove_lock_file_when_released() {
+        let tmp = TempDir::new().unwrap();
+
+        let config = Config::mock();
+        let secret = tmp.path().join("secret.pem");
+
+        let lock = SecretLock::acquire(&config, &secret).unwrap();
+        let path = lock.path.clone();
+        assert!(path.exists());
+        assert!(SecretLock::acquire(&config, &secret).is_err());
+
+        drop(lock);
+        assert!(!path.exists());
+
+        SecretLock::acquire(&config, &secret).unwrap();
+    }
+
+    #[test]
+    fn wait_for_the_lock() {
+        let tmp = TempDir::new().unwrap();
+
+        let config = Config::mock();
+
+        let lock = SecretLock::wait_manifest(&config, tmp.path()).unwrap();
+        let path = lock.path.clone();
+        assert!(path.starts_with(config.dirs.cache().unwrap()));
+
+        std::thread::scope(|scope| {
+            let waiting = scope.spawn(|| SecretLock::wait_manifest(&config, tmp.path()).map(drop));
+
+            std::thread::sleep(std::time::Duration::from_millis(100));
+            assert!(!waiting.is_finished());
+
+            drop(lock);
+            waiting.join().unwrap().unwrap();
+        });
+        assert!(!path.exists());
+    }
+}
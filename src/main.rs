@@ -1,7 +1,7 @@
 use clap::Parser;
-use cli::{Cli, Command};
+use cli::{Cli, Command, Git, Secret};
 use mctl::{CONFIG, config::Config};
-use tracing::level_filters::LevelFilter;
+use tracing::{level_filters::LevelFilter, warn};
 use tracing_subscriber::{EnvFilter, layer::SubscriberExt, util::SubscriberInitExt};
 
 mod cli;
@@ -24,14 +24,43 @@ fn main() -> eyre::Result<()> {
         return command.run();
     }
 
-    let config = Config::read(cli.config.as_deref())?;
+    // The git setup only changes the repository
+    if let Command::Git {
+        command: command @ Git::Setup { .. },
+    } = &cli.command
+    {
+        return command.run();
+    }
+
+    // The key files are created by the init command
+    let config = if let Command::Secret {
+        command: Secret::Init { .. },
+    } = cli.command
+    {
+        Config::read_unchecked(cli.config.as_deref())?
+    } else {
+        Config::read(cli.config.as_deref())?
+    };
 
     CONFIG.get_or_init(|| config);
 
+    // The git drivers run for every file of a diff or merge
+    if !matches!(cli.command, Command::Git { .. })
+        && let Err(err) = mctl::secret::remove_orphans()
+    {
+        warn!(error = %err, "couldn't remove the orphaned temporary files");
+    }
+
     match cli.command {
         Command::Secret { command } => {
             command.run()?;
         }
+        Command::Git { command } => {
+            command.run()?;
+        }
+        Command::Agent { sock, timeout } => {
+            mctl::agent::run(sock.as_deref(), timeout)?;
+        }
         Command::Utils { .. } => {}
     }
 
@@ -0,0 +1,152 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use eyre::{OptionExt, WrapErr, eyre};
+use tracing::debug;
+
+use crate::{
+    config::{Config, RecipientKey},
+    lock::SecretLock,
+    util::write_atomic,
+};
+
+/// Name of the manifest file in the directory of the secrets.
+pub(crate) const MANIFEST_FILE: &str = ".mctl-manifest";
+
+/// Records the fingerprints of the recipients each secret in a directory is encrypted to.
+///
+/// Each line contains the comma separated fingerprints followed by the secret file name.
+#[derive(Debug, Default)]
+pub(crate) struct Manifest {
+    path: PathBuf,
+    entries: BTreeMap<String, BTreeSet<String>>,
+}
+
+impl Manifest {
+    /// Reads the manifest of the directory, empty if it doesn't exist.
+    pub(crate) fn read(dir: &Path) -> eyre::Result<Self> {
+        let path = dir.join(MANIFEST_FILE);
+
+        debug!(path = %path.display(), "reading manifest");
+
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                return Ok(Self {
+                    path,
+                    entries: BTreeMap::new(),
+                });
+            }
+            Err(err) => {
+                return Err(err)
+                    .wrap_err_with(|| format!("couldn't read manifest: {}", path.display()));
+            }
+        };
+
+        let entries = content
+            .lines()
+            .enumerate()
+            .filter(|(_, l)| !(l.is_empty() || l.starts_with("#")))
+            .map(|(n, l)| {
+                let (fingerprints, name) = l.split_once(' ').ok_or_else(|| {
+                    eyre!(
+                        "invalid manifest entry on line {}: {}",
+                        n + 1,
+                        path.display()
+                    )
+                })?;
+
+                let fingerprints = fingerprints.split(',').map(str::to_string).collect();
+
+                Ok((name.to_string(), fingerprints))
+            })
+            .collect::<eyre::Result<BTreeMap<_, _>>>()?;
+
+        Ok(Self { path, entries })
+    }
+
+    /// Records the recipients a secret was encrypted to.
+    pub(crate) fn record(
+        config: &Config,
+        secret: &Path,
+        recipients: &[RecipientKey],
+    ) -> eyre::Result<()> {
+        Self::update(
+            config,
+            secret,
+            Some(recipients.iter().map(RecipientKey::fingerprint).collect()),
+        )
+    }
+
+    /// Replaces the recorded fingerprints of a secret, removing the entry if missing.
+    pub(crate) fn update(
+        config: &Config,
+        secret: &Path,
+        fingerprints: Option<BTreeSet<String>>,
+    ) -> eyre::Result<()> {
+        let (dir, name) = split_secret_path(secret)?;
+
+        // Serializes the updates between the threads and the processes, like when encrypting in
+        // parallel or from the git merge driver
+        let _lock = SecretLock::wait_manifest(config, dir)?;
+
+        let mut manifest = Self::read(dir)?;
+
+        match fingerprints {
+            Some(fingerprints) => {
+                manifest.entries.insert(name.to_string(), fingerprints);
+            }
+            None => {
+                manifest.entries.remove(name);
+            }
+        }
+
+        manifest.write()
+    }
+
+    /// Returns the fingerprints of the recipients of a secret, if recorded.
+    pub(crate) fn recipients(&self, secret: &Path) -> Option<&BTreeSet<String>> {
+        let name = secret.file_name()?.to_str()?;
+
+        self.entries.get(name)
+    }
+
+    fn write(&self) -> eyre::Result<()> {
+        debug!(path = %self.path.display(), "writing manifest");
+
+        write_atomic(&self.path, |file| {
+            writeln!(
+                file,
+                "# Recipients of the secrets in this directory, generated by mctl"
+            )?;
+
+            for (name, fingerprints) in &self.entries {
+                let fingerprints = fingerprints.iter().cloned().collect::<Vec<_>>().join(",");
+
+                writeln!(file, "{fingerprints} {name}")?;
+            }
+
+            Ok(())
+        })
+        .wrap_err_with(|| format!("couldn't write manifest: {}", self.path.display()))
+    }
+}
+
+/// Returns the directory and file name of a secret.
+pub(crate) fn split_secret_path(secret: &Path) -> eyre::Result<(&Path, &str)> {
+    let name = secret
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_eyre("secret file name is not valid UTF-8")?;
+
+    let dir = match secret.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+
+    Ok((dir, name))
+}
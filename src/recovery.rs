@@ -0,0 +1,312 @@
+//! Edits that couldn't be written to their secret, kept encrypted to apply them later.
+
+use std::{
+    cmp::Reverse,
+    ffi::OsString,
+    fs::{self, DirBuilder},
+    io::Read,
+    os::unix::{
+        ffi::{OsStrExt, OsStringExt},
+        fs::DirBuilderExt,
+    },
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use eyre::{WrapErr, eyre};
+use jiff::{SpanRelativeTo, Timestamp};
+use tracing::{debug, warn};
+use zeroize::Zeroizing;
+
+use crate::{
+    archive,
+    config::Config,
+    lock::SecretLock,
+    rules::absolute_secret_path,
+    secret::{decrypt, encrypt},
+    temp,
+    util::{create_private_file, random_alpha_num, remove_dir_securely, remove_securely},
+};
+
+/// Edit saved for recovery, the most recent is the number 1.
+#[derive(Debug)]
+pub(crate) struct Recovered {
+    pub(crate) number: usize,
+    pub(crate) timestamp: Timestamp,
+    /// Absolute path of the edited secret
+    pub(crate) secret: PathBuf,
+    path: PathBuf,
+}
+
+impl Recovered {
+    /// Decrypts the saved edit.
+    pub(crate) fn content(&self, config: &Config) -> eyre::Result<Zeroizing<Vec<u8>>> {
+        let raw = fs::read(&self.path)
+            .wrap_err_with(|| format!("couldn't read recovery file: {}", self.path.display()))?;
+
+        let mut content = Zeroizing::new(Vec::new());
+        decrypt(config, &mut raw.as_slice(), &mut *content)?;
+
+        Ok(content)
+    }
+
+    pub(crate) fn remove(&self) -> eyre::Result<()> {
+        debug!(path = %self.path.display(), "removing recovery file");
+
+        fs::remove_file(&self.path)?;
+        fs::remove_file(self.path.with_extension("path"))?;
+
+        Ok(())
+    }
+}
+
+fn recovery_dir(config: &Config) -> eyre::Result<PathBuf> {
+    let dir = config.dirs.cache()?.join("recovery");
+
+    DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(&dir)
+        .wrap_err_with(|| format!("couldn't create recovery directory: {}", dir.display()))?;
+
+    Ok(dir)
+}
+
+/// Encrypts an edit of a secret to the user's own identities, returning the file.
+///
+/// The rules of the secret may not include the user, who still has to decrypt the edit.
+pub(crate) fn save<R>(config: &Config, secret: &Path, plaintext: &mut R) -> eyre::Result<PathBuf>
+where
+    R: Read,
+{
+    let dir = recovery_dir(config)?;
+    let recipients = config.own_recipients()?;
+
+    let name = format!(
+        "{}-{}",
+        Timestamp::now().as_millisecond(),
+        random_alpha_num()
+    );
+    let path = dir.join(name).with_extension("pem");
+
+    // The secret path first, so the listed edits are complete
+    fs::write(
+        path.with_extension("path"),
+        absolute_secret_path(secret)?.as_os_str().as_bytes(),
+    )?;
+
+    let mut file = create_private_file(&path)?;
+    encrypt(&recipients, plaintext, &mut file).wrap_err("couldn't encrypt the edit")?;
+    file.sync_all()?;
+
+    debug!(path = %path.display(), "saved edit for recovery");
+
+    Ok(path)
+}
+
+/// Returns the saved edits, from the most recent.
+pub(crate) fn list(config: &Config) -> eyre::Result<Vec<Recovered>> {
+    let dir = recovery_dir(config)?;
+
+    let mut recovered = Vec::new();
+
+    for entry in fs::read_dir(&dir).wrap_err("couldn't read recovery directory")? {
+        let path = entry?.path();
+
+        if path.extension().is_none_or(|ext| ext != "pem") {
+            continue;
+        }
+
+        let Some(timestamp) = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| stem.split_once('-'))
+            .and_then(|(ms, _)| ms.parse::<i64>().ok())
+            .and_then(|ms| Timestamp::from_millisecond(ms).ok())
+        else {
+            warn!(path = %path.display(), "invalid recovery file name");
+
+            continue;
+        };
+
+        let secret = fs::read(path.with_extension("path"))
+            .map(|secret| PathBuf::from(OsString::from_vec(secret)))
+            .wrap_err_with(|| format!("couldn't read the secret path of {}", path.display()))?;
+
+        recovered.push(Recovered {
+            number: 0,
+            timestamp,
+            secret,
+            path,
+        });
+    }
+
+    recovered.sort_unstable_by_key(|recovered| Reverse(recovered.timestamp));
+
+    for (idx, recovered) in recovered.iter_mut().enumerate() {
+        recovered.number = idx + 1;
+    }
+
+    Ok(recovered)
+}
+
+/// Returns the saved edit with the given number.
+pub(crate) fn get(config: &Config, number: usize) -> eyre::Result<Recovered> {
+    list(config)?
+        .into_iter()
+        .find(|recovered| recovered.number == number)
+        .ok_or_else(|| eyre!("no recovered edit {number}"))
+}
+
+/// Removes the plaintext temporary files and extracted archives of the edits interrupted for
+/// longer than the maximum age, like when mctl was killed.
+///
+/// The files of the edits still running are kept, however old they are.
+///
+/// Checks the cache and the in memory directories, since the strategy may have changed.
+pub(crate) fn sweep(config: &Config) -> eyre::Result<()> {
+    let max_age = config
+        .recovery
+        .orphan_age
+        .to_duration(SpanRelativeTo::days_are_24_hours())
+        .wrap_err("invalid recovery orphan age")?;
+
+    for dir in temp::dirs(config)? {
+        if !dir.is_dir() {
+            continue;
+        }
+
+        for entry in fs::read_dir(&dir)
+            .wrap_err_with(|| format!("couldn't read directory: {}", dir.display()))?
+        {
+            let entry = entry?;
+            let path = entry.path();
+            let file_type = entry.file_type()?;
+
+            let is_archive_dir = file_type.is_dir() && is_temp_archive_dir(&path);
+
+            if !(file_type.is_file() && is_temp_file(&path)) && !is_archive_dir {
+                continue;
+            }
+
+            let modified = entry.metadata()?.modified()?;
+            let age = SystemTime::now()
+                .duration_since(modified)
+                .unwrap_or_default();
+
+            if age < max_age.unsigned_abs() {
+                continue;
+            }
+
+            // Released after the removal, which also removes the lock file
+            let Some(_in_use) = SecretLock::try_lock(temp::in_use_lock_path(&path))? else {
+                debug!(path = %path.display(), "temporary file still in use");
+
+                continue;
+            };
+
+            warn!(path = %path.display(), "removing orphaned temporary file");
+
+            if is_archive_dir {
+                remove_dir_securely(&path)?;
+            } else {
+                remove_securely(&path)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks the name of the temporary files of the edits, random alphanumeric with an optional
+/// extension.
+fn is_temp_file(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| name.split('.').next())
+        .is_some_and(|stem| stem.len() == 8 && stem.chars().all(|c| c.is_ascii_alphanumeric()))
+}
+
+/// Checks the name of the directories of the extracted archives, with the archive extension
+/// to not match the directories of the cache.
+fn is_temp_archive_dir(path: &Path) -> bool {
+    is_temp_file(path)
+        && path
+            .extension()
+            .is_some_and(|ext| ext == archive::ARCHIVE_EXTENSION)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn save_for_own_identity() {
+        let tmp = TempDir::new().unwrap();
+
+        // The team recipients don't include the user
+        let recipients = tmp.path().join("recipients.txt");
+        fs::write(
+            &recipients,
+            "age18kpe8fr5va7n837kxc3v2dpsh73gptt6wm3xsguj45l9xksph56q55jegd\n",
+        )
+        .unwrap();
+        fs::set_permissions(&recipients, fs::Permissions::from_mode(0o600)).unwrap();
+
+        let config = Config::mock().with_recipients_file(recipients);
+
+        save(
+            &config,
+            &tmp.path().join("secret.pem"),
+            &mut "edit".as_bytes(),
+        )
+        .unwrap();
+
+        let recovered = list(&config).unwrap();
+        assert_eq!(recovered.len(), 1);
+        assert_eq!(recovered[0].content(&config).unwrap().as_slice(), b"edit");
+    }
+
+    #[test]
+    fn sweep_orphaned_temp_files() {
+        let tmp = TempDir::new().unwrap();
+
+        let mut config = Config::mock().with_cache_dir(tmp.path());
+
+        let orphan = tmp.path().join("aB3dE6gH.toml");
+        let other = tmp.path().join("notes.txt");
+        fs::write(&orphan, "password = \"hunter2\"").unwrap();
+        fs::write(&other, "keep").unwrap();
+
+        let orphan_dir = tmp.path().join("xY7zW1vU.tar");
+        let other_dir = tmp.path().join("recovery");
+        fs::create_dir_all(orphan_dir.join("keys")).unwrap();
+        fs::write(orphan_dir.join("keys/id_ed25519"), "private key").unwrap();
+        fs::create_dir(&other_dir).unwrap();
+
+        config.recovery.orphan_age = jiff::Span::new().hours(1);
+        sweep(&config).unwrap();
+        assert!(orphan.exists());
+        assert!(orphan_dir.exists());
+
+        config.recovery.orphan_age = jiff::Span::new();
+
+        // The files of a running edit are kept
+        let in_use = temp::lock_in_use(&orphan).unwrap();
+        sweep(&config).unwrap();
+        assert!(orphan.exists());
+        assert!(!orphan_dir.exists());
+        drop(in_use);
+
+        sweep(&config).unwrap();
+        assert!(!orphan.exists());
+        assert!(!temp::in_use_lock_path(&orphan).exists());
+        assert!(other.exists());
+        assert!(!orphan_dir.exists());
+        assert!(other_dir.exists());
+    }
+}
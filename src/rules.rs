@@ -0,0 +1,195 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use color_eyre::{Section, owo_colors::OwoColorize};
+use config::FileFormat;
+use eyre::{OptionExt, WrapErr, ensure, eyre};
+use glob::{MatchOptions, Pattern};
+use serde::Deserialize;
+use tracing::debug;
+
+use crate::config::{Config, RecipientKey, parse_recipient, read_recipients_file};
+use crate::encoding::{Encoding, OutputFormat};
+
+/// Name of the rules file searched in the secret directory and its parents.
+const RULES_FILE: &str = ".mctl.toml";
+
+const MATCH_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+/// Rules to choose the recipients of the secrets in a directory.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Rules {
+    /// Directory of the rules file, the rule paths are relative to it.
+    #[serde(skip)]
+    dir: PathBuf,
+    #[serde(default)]
+    rules: Vec<Rule>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Rule {
+    /// Glob matching the secret paths
+    path: String,
+    /// File with the recipients, relative to the rules file
+    #[serde(default)]
+    recipients_file: Option<PathBuf>,
+    /// Inline age or SSH recipients
+    #[serde(default)]
+    recipients: Vec<String>,
+    /// Recipient groups defined in the configuration
+    #[serde(default)]
+    groups: Vec<String>,
+    /// Armored or binary, from the extension of the secret by default
+    #[serde(default)]
+    format: Option<OutputFormat>,
+    /// Compress the plaintext before encrypting it
+    #[serde(default)]
+    compress: bool,
+}
+
+impl Rules {
+    /// Finds the closest rules file walking up from the secret directory.
+    fn find(secret: &Path) -> eyre::Result<Option<Self>> {
+        let secret = absolute_secret_path(secret)?;
+
+        let Some(rules_file) = secret
+            .ancestors()
+            .skip(1)
+            .map(|dir| dir.join(RULES_FILE))
+            .find(|path| path.is_file())
+        else {
+            return Ok(None);
+        };
+
+        Self::read(&rules_file).map(Some)
+    }
+
+    fn read(path: &Path) -> eyre::Result<Self> {
+        debug!(file = %path.display(), "reading rules file");
+
+        let mut rules = config::Config::builder()
+            .add_source(config::File::from(path).format(FileFormat::Toml))
+            .build()
+            .and_then(|cfg| cfg.try_deserialize::<Rules>())
+            .wrap_err("couldn't read rules file")
+            .with_note(|| format!("Make sure {} is a valid rules file", path.display().blue()))?;
+
+        rules.dir = path
+            .parent()
+            .ok_or_eyre("rules file has no parent directory")?
+            .to_path_buf();
+
+        for rule in &rules.rules {
+            Pattern::new(&rule.path)
+                .wrap_err_with(|| format!("invalid path pattern: {}", rule.path))?;
+
+            ensure!(
+                rule.recipients_file.is_some()
+                    || !rule.recipients.is_empty()
+                    || !rule.groups.is_empty(),
+                "rule for {} has no recipients",
+                rule.path
+            );
+        }
+
+        Ok(rules)
+    }
+
+    /// Returns the first rule matching the secret.
+    fn rule(&self, secret: &Path) -> eyre::Result<Option<&Rule>> {
+        let secret = absolute_secret_path(secret)?;
+        let Ok(relative) = secret.strip_prefix(&self.dir) else {
+            return Ok(None);
+        };
+
+        let rule = self.rules.iter().find(|rule| {
+            Pattern::new(&rule.path)
+                .is_ok_and(|pattern| pattern.matches_path_with(relative, MATCH_OPTIONS))
+        });
+
+        if let Some(rule) = rule {
+            debug!(path = rule.path, secret = %relative.display(), "matched rule");
+        }
+
+        Ok(rule)
+    }
+
+    /// Returns the recipients of the first rule matching the secret.
+    fn recipients(
+        &self,
+        config: &Config,
+        secret: &Path,
+    ) -> eyre::Result<Option<Vec<RecipientKey>>> {
+        let Some(rule) = self.rule(secret)? else {
+            return Ok(None);
+        };
+
+        let mut recipients = rule
+            .recipients
+            .iter()
+            .map(|line| parse_recipient(line))
+            .collect::<eyre::Result<Vec<_>>>()
+            .wrap_err_with(|| format!("invalid recipient in rule for {}", rule.path))?;
+
+        if let Some(file) = &rule.recipients_file {
+            recipients.extend(read_recipients_file(&self.dir.join(file))?);
+        }
+
+        recipients.extend(config.secrets.groups_recipients(&rule.groups)?);
+
+        Ok(Some(recipients))
+    }
+}
+
+/// Returns the recipients for a secret, from the rules or the global configuration.
+pub(crate) fn recipients_for(config: &Config, secret: &Path) -> eyre::Result<Vec<RecipientKey>> {
+    if let Some(rules) = Rules::find(secret)?
+        && let Some(recipients) = rules.recipients(config, secret)?
+    {
+        return Ok(recipients);
+    }
+
+    config.secrets.recipients()
+}
+
+/// Returns the encoding of a secret, from the rules or its extension.
+pub(crate) fn encoding_for(secret: &Path) -> eyre::Result<Encoding> {
+    let mut encoding = Encoding::from_extension(secret);
+
+    if let Some(rules) = Rules::find(secret)?
+        && let Some(rule) = rules.rule(secret)?
+    {
+        if let Some(format) = rule.format {
+            encoding.format = format;
+        }
+
+        encoding.compress = rule.compress;
+    }
+
+    Ok(encoding)
+}
+
+/// Returns the absolute path of a secret that may not exist yet.
+pub(crate) fn absolute_secret_path(secret: &Path) -> eyre::Result<PathBuf> {
+    let file_name = secret
+        .file_name()
+        .ok_or_else(|| eyre!("invalid secret path: {}", secret.display()))?;
+
+    let parent = match secret.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+
+    let parent = fs::canonicalize(parent)
+        .wrap_err_with(|| format!("couldn't resolve directory: {}", parent.display()))?;
+
+    Ok(parent.join(file_name))
+}
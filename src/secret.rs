@@ -1,90 +1,189 @@
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, BTreeSet};
 use std::ffi::{OsStr, OsString};
-use std::fs::{self, File};
-use std::io::{self, stdin, stdout};
-use std::os::unix::fs::OpenOptionsExt;
+use std::fs::{self, DirBuilder, File};
+use std::io::{self, Read, Write, stdin, stdout};
+use std::mem;
+use std::os::fd::OwnedFd;
+use std::os::unix::fs::{DirBuilderExt, FileExt, OpenOptionsExt, PermissionsExt};
+use std::os::unix::process::CommandExt;
 use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::process::{Child, Command};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, PoisonError};
 
 use age::armor::{ArmoredReader, ArmoredWriter};
-use age::{Decryptor, Identity, Recipient};
+use age::secrecy::ExposeSecret;
+use age::{DecryptError, Decryptor, Identity};
 use blake3::Hash;
 use color_eyre::{Section, owo_colors::OwoColorize};
-use eyre::{Context, bail, eyre};
-use tracing::{debug, error, info};
-
-use crate::{config::Config, util::random_alpha_num};
+use eyre::{Context, OptionExt, bail, eyre};
+use regex::bytes::Regex;
+use tracing::{debug, error, info, warn};
+use zeroize::Zeroizing;
+
+use crate::agent;
+use crate::archive::{self, ARCHIVE_EXTENSION};
+use crate::config::{Config, RecipientKey, read_recipient_lines};
+use crate::document::{Document, Format, dotenv_vars};
+use crate::encoding::{BINARY_EXTENSION, Encoding, read_plaintext, write_plaintext};
+use crate::generate::Generator;
+use crate::header::{Header, ssh_recipient_tag};
+use crate::history;
+use crate::lock::SecretLock;
+use crate::manifest::{Manifest, split_secret_path};
+use crate::recovery;
+use crate::rules;
+use crate::temp::{self, TempLocation};
+use crate::template::{self, Part};
+use crate::util::{create_private_file, random_alpha_num, remove_dir_securely, write_atomic};
+
+/// Encrypts to an armored age file.
+pub(crate) fn encrypt<R, W>(
+    recipients: &[RecipientKey],
+    reader: &mut R,
+    writer: &mut W,
+) -> eyre::Result<()>
+where
+    R: std::io::Read,
+    W: std::io::Write,
+{
+    encrypt_with(recipients, Encoding::default(), reader, writer)
+}
 
-fn encrypt<R, W>(config: &Config, reader: &mut R, writer: &mut W) -> eyre::Result<()>
+/// Encrypts to an armored or binary age file, compressing the plaintext if needed.
+pub(crate) fn encrypt_with<R, W>(
+    recipients: &[RecipientKey],
+    encoding: Encoding,
+    reader: &mut R,
+    writer: &mut W,
+) -> eyre::Result<()>
 where
     R: std::io::Read,
     W: std::io::Write,
 {
-    let recipients = config.secrets.recipients()?;
-    let recipients = recipients.iter().map(|r| r as &dyn Recipient);
+    encrypt_stream(recipients, encoding, writer, |plaintext| {
+        io::copy(reader, plaintext)?;
+
+        Ok(())
+    })
+}
+
+/// Encrypts the plaintext written by the callback, like a tar archive.
+pub(crate) fn encrypt_stream<W, F>(
+    recipients: &[RecipientKey],
+    encoding: Encoding,
+    writer: &mut W,
+    write: F,
+) -> eyre::Result<()>
+where
+    W: std::io::Write,
+    F: FnOnce(&mut dyn Write) -> eyre::Result<()>,
+{
+    let recipients = recipients.iter().map(RecipientKey::recipient);
 
     let encriptor = age::Encryptor::with_recipients(recipients)?;
-    let mut writer = encriptor.wrap_output(ArmoredWriter::wrap_output(
-        writer,
-        age::armor::Format::AsciiArmor,
-    )?)?;
+    let mut writer =
+        encriptor.wrap_output(ArmoredWriter::wrap_output(writer, encoding.format.into())?)?;
 
-    io::copy(reader, &mut writer)?;
+    write_plaintext(encoding.compress, &mut writer, write)?;
 
     writer.finish().and_then(|armor| armor.finish())?;
 
     Ok(())
 }
 
-fn decrypt<R, W>(config: &Config, reader: &mut R, dst: &mut W) -> eyre::Result<()>
+pub(crate) fn decrypt<R, W>(config: &Config, reader: &mut R, dst: &mut W) -> eyre::Result<()>
 where
     R: std::io::Read,
     W: std::io::Write,
 {
-    let identities = config.secrets.identity()?;
+    decrypt_stream(config, reader, |plaintext| {
+        io::copy(plaintext, dst).wrap_err("couldn't copy to destination")?;
 
-    let decryptor = Decryptor::new(ArmoredReader::new(reader))?;
-    let mut stream = decryptor.decrypt(std::iter::once(&identities as &dyn Identity))?;
+        Ok(())
+    })
+}
 
-    io::copy(&mut stream, dst).wrap_err("couldn't copy to destination")?;
+/// Decrypts and passes the plaintext to the callback, like to extract a tar archive.
+pub(crate) fn decrypt_stream<R, F>(config: &Config, reader: &mut R, read: F) -> eyre::Result<()>
+where
+    R: std::io::Read,
+    F: FnOnce(&mut dyn Read) -> eyre::Result<()>,
+{
+    let identities = agent::identities(config)?;
+
+    decrypt_stream_with(&identities, reader, read).map_err(|err| {
+        match err.downcast_ref::<DecryptError>() {
+            Some(DecryptError::NoMatchingKeys) => {
+                let tried = config
+                    .secrets
+                    .key_files()
+                    .iter()
+                    .map(|path| path.display().to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ");
+
+                err.note(format!("tried the keys in: {}", tried.blue()))
+            }
+            _ => err,
+        }
+    })
+}
 
-    Ok(())
+/// Decrypts with the given identities.
+pub(crate) fn decrypt_with<R, W>(
+    identities: &[Box<dyn Identity>],
+    reader: &mut R,
+    dst: &mut W,
+) -> eyre::Result<()>
+where
+    R: std::io::Read,
+    W: std::io::Write,
+{
+    decrypt_stream_with(identities, reader, |plaintext| {
+        io::copy(plaintext, dst).wrap_err("couldn't copy to destination")?;
+
+        Ok(())
+    })
+}
+
+fn decrypt_stream_with<R, F>(
+    identities: &[Box<dyn Identity>],
+    reader: &mut R,
+    read: F,
+) -> eyre::Result<()>
+where
+    R: std::io::Read,
+    F: FnOnce(&mut dyn Read) -> eyre::Result<()>,
+{
+    let decryptor = Decryptor::new(ArmoredReader::new(reader))?;
+    let mut stream = decryptor.decrypt(identities.iter().map(|i| i.as_ref()))?;
+
+    read_plaintext(&mut stream, read)
 }
 
 struct TempFile {
     path: PathBuf,
+    // Keeps the memory file alive, it's freed when closed
+    memfd: Option<OwnedFd>,
+    // Keeps the file from being removed as orphaned, released after the file is removed
+    _in_use: Option<SecretLock>,
     // Hash before an edit
     hash: Option<Hash>,
+    // Hash of the ciphertext that was decrypted, to detect concurrent changes
+    secret_hash: Option<Hash>,
 }
 
 impl TempFile {
     fn create(&self) -> eyre::Result<File> {
         debug!(path = %self.path.display(), "creating temporary file");
 
-        let file = File::options()
-            .create(true)
-            .truncate(true)
-            .write(true)
-            .read(true)
-            .mode(0o600)
-            .open(&self.path)
-            .wrap_err("couldn't open temporary file")?;
-
-        Ok(file)
+        create_private_file(&self.path).wrap_err("couldn't open temporary file")
     }
 
-    fn from_secret(secret_path: &Path, cache_dir: &Path) -> Self {
-        let ext = secret_path
-            .file_name()
-            .and_then(OsStr::to_str)
-            .and_then(|filename| {
-                let without_pem = filename.strip_suffix(".pem").unwrap_or(filename);
-
-                without_pem.rsplit_once(".")
-            })
-            .map(|(_file, ext)| ext)
-            .filter(|ext| !ext.is_empty());
-
-        Self::new(cache_dir, ext)
+    fn from_secret(secret_path: &Path, location: &TempLocation) -> eyre::Result<Self> {
+        Self::new(location, secret_extension(secret_path))
     }
 
     fn open(&self) -> eyre::Result<File> {
@@ -95,19 +194,63 @@ impl TempFile {
             .wrap_err("couldn't open temporary file")
     }
 
-    fn new(cache_dir: &Path, ext: Option<&str>) -> Self {
-        let mut tmp_name = OsString::from(random_alpha_num());
+    fn new(location: &TempLocation, ext: Option<&str>) -> eyre::Result<Self> {
+        let mut tmp_name = random_alpha_num();
 
         if let Some(ext) = ext {
-            tmp_name.push(".");
-            tmp_name.push(ext);
+            tmp_name.push('.');
+            tmp_name.push_str(ext);
         }
 
-        let tpm_path = cache_dir.join(tmp_name);
+        let (path, memfd, in_use) = match location {
+            TempLocation::Dir(dir) => {
+                let path = dir.join(tmp_name);
+                let in_use = temp::lock_in_use(&path)?;
+
+                (path, None, Some(in_use))
+            }
+            TempLocation::Memfd => {
+                let (fd, path) = temp::memfd(&tmp_name)?;
+
+                (path, Some(fd), None)
+            }
+        };
 
-        Self {
-            path: tpm_path,
+        Ok(Self {
+            path,
+            memfd,
+            _in_use: in_use,
             hash: None,
+            secret_hash: None,
+        })
+    }
+
+    /// Spawns the editor on the file, the memory file is only inherited by the editor.
+    fn spawn_editor(&self, editor: &str) -> eyre::Result<Child> {
+        let mut command = Command::new(editor);
+
+        let _inherited = match &self.memfd {
+            Some(fd) => {
+                let (dup, path) = temp::inheritable(fd)?;
+                command.arg(path);
+
+                Some(dup)
+            }
+            None => {
+                command.arg(&self.path);
+
+                None
+            }
+        };
+
+        command.spawn().wrap_err("couldn't start the editor")
+    }
+
+    /// Checks the editor didn't change the file, or left it empty for a new secret.
+    fn unchanged(&self) -> bool {
+        match self.hash {
+            Some(hash) => self.hash() == Some(hash),
+            None => self.path.metadata().is_ok_and(|md| md.len() == 0),
         }
     }
 
@@ -126,12 +269,77 @@ impl TempFile {
 
 impl Drop for TempFile {
     fn drop(&mut self) {
+        if self.memfd.is_some() {
+            return;
+        }
+
         if let Err(err) = fs::remove_file(&self.path) {
             error!(error = %err, "couln't remove temporary file");
         }
     }
 }
 
+/// Private directory where an archive secret is extracted to be edited.
+struct TempArchive {
+    path: PathBuf,
+    // Keeps the directory from being removed as orphaned
+    _in_use: SecretLock,
+}
+
+impl TempArchive {
+    fn new(dir: &Path) -> eyre::Result<Self> {
+        let path = dir.join(format!("{}.{ARCHIVE_EXTENSION}", random_alpha_num()));
+
+        debug!(path = %path.display(), "creating temporary directory");
+
+        let in_use = temp::lock_in_use(&path)?;
+
+        DirBuilder::new()
+            .mode(0o700)
+            .create(&path)
+            .wrap_err("couldn't create temporary directory")?;
+
+        Ok(Self {
+            path,
+            _in_use: in_use,
+        })
+    }
+
+    /// Packs the directory in memory.
+    fn pack(&self) -> eyre::Result<Zeroizing<Vec<u8>>> {
+        let mut packed = Zeroizing::new(Vec::new());
+
+        archive::pack(&self.path, &mut *packed)?;
+
+        Ok(packed)
+    }
+}
+
+impl Drop for TempArchive {
+    fn drop(&mut self) {
+        if let Err(err) = remove_dir_securely(&self.path) {
+            error!(error = %err, "couln't remove temporary directory");
+        }
+    }
+}
+
+/// Returns the extension of the secret content, before the `.pem` or `.age` one.
+fn secret_extension(secret_path: &Path) -> Option<&str> {
+    secret_path
+        .file_name()
+        .and_then(OsStr::to_str)
+        .and_then(|filename| {
+            let without_ext = filename
+                .strip_suffix(".pem")
+                .or_else(|| filename.strip_suffix(".age"))
+                .unwrap_or(filename);
+
+            without_ext.rsplit_once(".")
+        })
+        .map(|(_file, ext)| ext)
+        .filter(|ext| !ext.is_empty())
+}
+
 struct SecretFile<'a> {
     path: &'a Path,
     allow_empty: bool,
@@ -142,24 +350,17 @@ impl<'a> SecretFile<'a> {
         Self { path, allow_empty }
     }
 
-    fn open(&self, truncate: bool) -> eyre::Result<File> {
+    fn open(&self) -> eyre::Result<File> {
         debug!(path = %self.path.display(), "opening secret file");
 
-        File::options()
-            .create(true)
-            .truncate(truncate)
-            .read(true)
-            .write(true)
-            .mode(0o600)
-            .open(self.path)
-            .wrap_err("couldn't open secret file")
+        File::open(self.path).wrap_err("couldn't open secret file")
     }
 
     fn decrypt_to<W>(&self, config: &Config, dst: &mut W) -> eyre::Result<()>
     where
         W: std::io::Write,
     {
-        let mut file = self.open(false)?;
+        let mut file = self.open()?;
 
         decrypt(config, &mut file, dst)
     }
@@ -168,18 +369,68 @@ impl<'a> SecretFile<'a> {
     where
         R: std::io::Read,
     {
-        let mut file = self.open(true)?;
+        self.encrypt_stream(config, |plaintext| {
+            io::copy(reader, plaintext)?;
+
+            Ok(())
+        })
+    }
+
+    /// Encrypts the plaintext written by the callback.
+    fn encrypt_stream<F>(&self, config: &Config, write: F) -> eyre::Result<()>
+    where
+        F: FnOnce(&mut dyn Write) -> eyre::Result<()>,
+    {
+        let recipients = rules::recipients_for(config, self.path)?;
+        let encoding = rules::encoding_for(self.path)?;
 
-        encrypt(config, reader, &mut file)?;
+        self.write_atomic(|file| encrypt_stream(&recipients, encoding, file, write))?;
 
-        file.sync_all()?;
+        Manifest::record(config, self.path, &recipients)
+    }
 
-        Ok(())
+    /// Checks if the secret is a tar archive of a directory, like `pki.tar.age`.
+    fn is_archive(&self) -> bool {
+        secret_extension(self.path) == Some(ARCHIVE_EXTENSION)
+    }
+
+    /// Decrypts the secret in memory.
+    fn decrypt_to_string(&self, config: &Config) -> eyre::Result<Zeroizing<String>> {
+        let mut content = Zeroizing::new(Vec::new());
+        self.decrypt_to(config, &mut *content)
+            .wrap_err_with(|| format!("couldn't decrypt {}", self.path.display()))?;
+
+        String::from_utf8(mem::take(&mut *content))
+            .map(Zeroizing::new)
+            .map_err(|err| {
+                // Zeroize the invalid content too
+                drop(Zeroizing::new(err.into_bytes()));
+
+                eyre!("secret is not valid UTF-8: {}", self.path.display())
+            })
+    }
+
+    /// Decrypts and parses a structured secret.
+    fn read_document(&self, config: &Config) -> eyre::Result<Document> {
+        let format = Format::from_extension(secret_extension(self.path))
+            .note("structured secrets must end in .toml.pem, .json.pem or .env.pem")?;
+
+        let content = self.decrypt_to_string(config)?;
+
+        Document::parse(format, &content)
+    }
+
+    /// Writes the secret to a sibling temporary file and renames it over the original.
+    fn write_atomic<F>(&self, write: F) -> eyre::Result<()>
+    where
+        F: FnOnce(&mut File) -> eyre::Result<()>,
+    {
+        write_atomic(self.path, write)
     }
 
     /// Decrypts the secret to a temp file, returning the hash if the secret already exists
     fn decrypt_to_tmp(&self, config: &Config) -> eyre::Result<TempFile> {
-        let mut tmp = TempFile::from_secret(self.path, config.dirs.cache()?);
+        let mut tmp = TempFile::from_secret(self.path, &temp::location(config)?)?;
 
         if !self.path.try_exists()? {
             info!("new secret file");
@@ -189,9 +440,12 @@ impl<'a> SecretFile<'a> {
 
         info!("decrypting secret file");
 
+        let raw = fs::read(self.path).wrap_err("couldn't read secret file")?;
+        tmp.secret_hash = Some(blake3::hash(&raw));
+
         let mut tmp_file = tmp.create()?;
 
-        self.decrypt_to(config, &mut tmp_file)
+        decrypt(config, &mut raw.as_slice(), &mut tmp_file)
             .wrap_err("couldn't decrypt to temp file")?;
 
         tmp_file.sync_all()?;
@@ -221,6 +475,10 @@ impl<'a> SecretFile<'a> {
             }
         }
 
+        self.check_unchanged(config, tmp.secret_hash, || tmp.open())?;
+
+        history::save(config, self.path).wrap_err("couldn't save the previous version")?;
+
         info!("encrypt the secret file");
 
         let mut tmp_file = tmp.open()?;
@@ -231,7 +489,60 @@ impl<'a> SecretFile<'a> {
         Ok(())
     }
 
+    /// Checks that the secret on disk is still the one decrypted to edit it.
+    ///
+    /// The edit is saved for recovery on error, to not lose the changes.
+    fn check_unchanged<R, F>(
+        &self,
+        config: &Config,
+        secret_hash: Option<Hash>,
+        edited: F,
+    ) -> eyre::Result<()>
+    where
+        R: Read,
+        F: FnOnce() -> eyre::Result<R>,
+    {
+        let current = match fs::read(self.path) {
+            Ok(raw) => Some(blake3::hash(&raw)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+            Err(err) => return Err(err).wrap_err("couldn't read secret file"),
+        };
+
+        if current == secret_hash {
+            return Ok(());
+        }
+
+        let err = eyre!("the secret changed while editing: {}", self.path.display());
+
+        Err(self.save_for_recovery(config, edited, err))
+    }
+
+    /// Encrypts the edited plaintext for recovery, adding how to apply it to the error.
+    fn save_for_recovery<R, F>(&self, config: &Config, edited: F, err: eyre::Report) -> eyre::Report
+    where
+        R: Read,
+        F: FnOnce() -> eyre::Result<R>,
+    {
+        let saved =
+            edited().and_then(|mut plaintext| recovery::save(config, self.path, &mut plaintext));
+
+        match saved {
+            Ok(_) => err.note(format!(
+                "your changes are saved, apply them with {}",
+                "mctl secret recover".blue()
+            )),
+            Err(save_err) => err.note(format!("couldn't save your changes: {save_err:#}")),
+        }
+    }
+
+    /// Locks the secret for the whole decrypt, edit and encrypt.
+    fn lock(&self, config: &Config) -> eyre::Result<SecretLock> {
+        SecretLock::acquire(config, self.path)
+    }
+
     fn rotate(&self, config: &Config) -> eyre::Result<()> {
+        let _lock = self.lock(config)?;
+
         let mut tmp = self.decrypt_to_tmp(config)?;
         // Force re-encryption
         tmp.hash.take();
@@ -246,23 +557,144 @@ pub fn edit(secret_path: &Path, allow_empty: bool) -> eyre::Result<()> {
 
     let secret_file = SecretFile::new(secret_path, allow_empty);
 
+    let _lock = secret_file.lock(config)?;
+
+    if secret_file.is_archive() {
+        return edit_archive(config, &secret_file);
+    }
+
     let tmp = secret_file.decrypt_to_tmp(config)?;
 
+    let out = tmp.spawn_editor(&config.editor)?.wait_with_output()?;
+
+    if !out.status.success() {
+        error!(
+            status = out.status.code(),
+            "editor exited with an error status code"
+        );
+
+        let err = eyre!("editor exited with an error");
+
+        if tmp.hash() == tmp.hash {
+            return Err(err);
+        }
+
+        return Err(secret_file.save_for_recovery(config, || tmp.open(), err));
+    }
+
+    if tmp.memfd.is_some() && tmp.unchanged() {
+        warn!(
+            "the memory file wasn't changed, if you saved it check that the editor writes the \
+             file in place instead of replacing it"
+        );
+    }
+
+    secret_file.encrypt_from_tmp(config, tmp)?;
+
+    Ok(())
+}
+
+/// Extracts an archive secret to a private directory, opens the editor on it and packs it back.
+fn edit_archive(config: &Config, secret_file: &SecretFile) -> eyre::Result<()> {
+    let tmp = TempArchive::new(&temp::dir(config)?)?;
+
+    let secret_hash = if secret_file.path.try_exists()? {
+        info!("extracting archive");
+
+        let raw = fs::read(secret_file.path).wrap_err("couldn't read secret file")?;
+
+        decrypt_stream(config, &mut raw.as_slice(), |archive| {
+            archive::unpack(archive, &tmp.path)
+        })
+        .wrap_err("couldn't extract the archive")?;
+
+        Some(blake3::hash(&raw))
+    } else {
+        info!("new archive");
+
+        None
+    };
+
+    let before = blake3::hash(&tmp.pack()?);
+
     let out = Command::new(&config.editor)
         .arg(&tmp.path)
         .spawn()?
         .wait_with_output()?;
 
+    let packed = tmp.pack()?;
+    let changed = blake3::hash(&packed) != before;
+
     if !out.status.success() {
         error!(
             status = out.status.code(),
             "editor exited with an error status code"
         );
 
-        bail!("editor exited with an error");
+        let err = eyre!("editor exited with an error");
+
+        if !changed {
+            return Err(err);
+        }
+
+        return Err(secret_file.save_for_recovery(config, || Ok(packed.as_slice()), err));
     }
 
-    secret_file.encrypt_from_tmp(config, tmp)?;
+    if !changed {
+        info!("the archive is still the same");
+
+        return Ok(());
+    }
+
+    secret_file.check_unchanged(config, secret_hash, || Ok(packed.as_slice()))?;
+
+    history::save(config, secret_file.path).wrap_err("couldn't save the previous version")?;
+
+    info!("encrypt the archive");
+
+    secret_file.encrypt_from(config, &mut packed.as_slice())
+}
+
+/// Encrypts a tar archive of a directory, with the modes and modification times of the files.
+pub fn pack(dir: &Path, file: &Path) -> eyre::Result<()> {
+    let config = crate::config();
+
+    if !dir.is_dir() {
+        bail!("not a directory: {}", dir.display());
+    }
+
+    let secret_file = SecretFile::new(file, false);
+
+    if !secret_file.is_archive() {
+        warn!(
+            file = %file.display(),
+            "the archive is edited as a directory only if it ends in .tar.age or .tar.pem"
+        );
+    }
+
+    let _lock = secret_file.lock(config)?;
+
+    history::save(config, file).wrap_err("couldn't save the previous version")?;
+
+    secret_file
+        .encrypt_stream(config, |plaintext| archive::pack(dir, plaintext))
+        .wrap_err_with(|| format!("couldn't pack {}", dir.display()))?;
+
+    info!(file = %file.display(), "directory packed");
+
+    Ok(())
+}
+
+/// Extracts an encrypted tar archive in a directory, refusing the files outside of it.
+pub fn unpack(file: &Path, dir: &Path) -> eyre::Result<()> {
+    let config = crate::config();
+
+    let mut secret = SecretFile::new(file, false).open()?;
+
+    decrypt_stream(config, &mut secret, |archive| archive::unpack(archive, dir))
+        .wrap_err_with(|| format!("couldn't unpack {}", file.display()))?;
+
+    info!(dir = %dir.display(), "archive unpacked");
 
     Ok(())
 }
@@ -272,13 +704,31 @@ pub fn from_stdin(allow_empty: bool, file: &Path) -> eyre::Result<()> {
 
     let mut stdin = stdin().lock();
 
-    let tmp = TempFile::new(config.dirs.cache()?, None);
+    SecretFile::new(file, allow_empty).encrypt_from(config, &mut stdin)?;
+
+    info!("secret encrypted");
 
-    SecretFile::new(&tmp.path, allow_empty).encrypt_from(config, &mut stdin)?;
+    Ok(())
+}
 
-    fs::copy(&tmp.path, file).wrap_err("couldn't copy temp file")?;
+/// Generates a new secret value and encrypts it, without writing the plaintext to disk.
+pub fn generate(file: &Path, generator: &Generator, force: bool) -> eyre::Result<()> {
+    let config = crate::config();
 
-    info!("secret encrypted");
+    if !force && file.exists() {
+        return Err(eyre!("secret already exists: {}", file.display()))
+            .with_note(|| format!("pass {} to overwrite it", "--force".blue()));
+    }
+
+    let generated = generator.generate()?;
+
+    SecretFile::new(file, false).encrypt_from(config, &mut generated.value.as_bytes())?;
+
+    info!(file = %file.display(), "secret generated");
+
+    if let Some(public_key) = generated.public_key {
+        writeln!(stdout(), "{public_key}")?;
+    }
 
     Ok(())
 }
@@ -295,185 +745,2450 @@ pub fn cat(file: &Path) -> eyre::Result<()> {
     Ok(())
 }
 
-pub fn rotate(file: &Path) -> eyre::Result<()> {
+/// Prints a single field of a structured secret.
+pub fn get(file: &Path, key: &str) -> eyre::Result<()> {
     let config = crate::config();
 
-    SecretFile::new(file, true).rotate(config)?;
+    let document = SecretFile::new(file, false).read_document(config)?;
 
-    info!("secret encrypted");
+    let value = Zeroizing::new(document.get(key)?);
+
+    writeln!(stdout(), "{}", value.as_str())?;
 
     Ok(())
 }
 
-#[cfg(test)]
-mod tests {
-    use std::io::Cursor;
+/// Updates a single field of a structured secret, reading the value from stdin if missing.
+pub fn set(file: &Path, key: &str, value: Option<&str>) -> eyre::Result<()> {
+    let config = crate::config();
 
-    use pretty_assertions::assert_ne;
-    use tempfile::TempDir;
+    let value = match value {
+        Some(value) => Zeroizing::new(value.to_string()),
+        None => {
+            let mut value = Zeroizing::new(String::new());
+            stdin()
+                .read_to_string(&mut value)
+                .wrap_err("couldn't read the value from stdin")?;
 
-    use super::*;
+            if value.ends_with('\n') {
+                value.pop();
+            }
 
-    #[test]
-    fn encrypt_and_decrypt() {
-        let tmp = TempDir::new().unwrap();
+            value
+        }
+    };
 
-        let file = tmp.path().join("secret.txt.pem");
+    let secret = SecretFile::new(file, false);
 
-        let plaintext = "Hello World!";
-        let mut reader = Cursor::new(plaintext);
+    set_field(config, &secret, key, &value)?;
 
-        let config = Config::mock();
+    info!(key, "secret updated");
 
-        SecretFile::new(&file, false)
-            .encrypt_from(&config, &mut reader)
-            .unwrap();
+    Ok(())
+}
 
-        let mut out = Cursor::new(Vec::new());
+fn set_field(config: &Config, secret: &SecretFile, key: &str, value: &str) -> eyre::Result<()> {
+    let _lock = secret.lock(config)?;
 
-        SecretFile::new(&file, false)
-            .decrypt_to(&config, &mut out)
-            .unwrap();
+    let mut document = secret.read_document(config)?;
 
-        let inner = out.into_inner();
-        let out = str::from_utf8(&inner).unwrap();
+    document.set(key, value)?;
 
-        assert_eq!(out, plaintext);
-    }
+    let content = document.serialize()?;
 
-    #[test]
-    fn encrypt_and_decrypt_smaller() {
-        let tmp = TempDir::new().unwrap();
+    secret.encrypt_from(config, &mut content.as_bytes())
+}
 
-        let file = tmp.path().join("secret.txt.pem");
+/// Runs a command with the secrets as environment variables.
+///
+/// The secrets are decrypted in memory and the process is replaced by the command, so it
+/// receives the signals directly and its exit status is the one of mctl.
+pub fn exec(
+    vars: &[(String, PathBuf)],
+    env_files: &[PathBuf],
+    command: &[OsString],
+) -> eyre::Result<()> {
+    let config = crate::config();
 
-        let config = Config::mock();
+    let (program, args) = command.split_first().ok_or_eyre("missing command to run")?;
 
-        let plaintext = "Hello World!";
-        let mut reader = Cursor::new(plaintext);
+    let env = secrets_env(config, vars, env_files)?;
 
-        SecretFile::new(&file, false)
-            .encrypt_from(&config, &mut reader)
-            .unwrap();
+    debug!(program = %program.to_string_lossy(), vars = env.len(), "executing command");
 
-        let plaintext = "Hello";
-        let mut reader = Cursor::new(plaintext);
+    let err = Command::new(program)
+        .args(args)
+        .envs(env.iter().map(|(name, value)| (name, value.as_str())))
+        .exec();
 
-        SecretFile::new(&file, false)
-            .encrypt_from(&config, &mut reader)
-            .unwrap();
+    Err(err).wrap_err_with(|| format!("couldn't execute {}", program.to_string_lossy()))
+}
 
-        let mut out = Cursor::new(Vec::new());
+/// Decrypts the environment variables, the ones from the files first.
+///
+/// The secrets of the single variables are trimmed of the trailing newlines, like a shell
+/// command substitution.
+fn secrets_env(
+    config: &Config,
+    vars: &[(String, PathBuf)],
+    env_files: &[PathBuf],
+) -> eyre::Result<Vec<(String, Zeroizing<String>)>> {
+    let mut env = Vec::new();
+
+    for file in env_files {
+        let content = SecretFile::new(file, true).decrypt_to_string(config)?;
+
+        env.extend(
+            dotenv_vars(&content)
+                .into_iter()
+                .map(|(name, value)| (name, Zeroizing::new(value))),
+        );
+    }
 
-        SecretFile::new(&file, false)
-            .decrypt_to(&config, &mut out)
-            .unwrap();
+    for (name, file) in vars {
+        let mut content = SecretFile::new(file, true).decrypt_to_string(config)?;
 
-        let inner = out.into_inner();
-        let out = str::from_utf8(&inner).unwrap();
+        let len = content.trim_end_matches(['\n', '\r']).len();
+        content.truncate(len);
 
-        assert_eq!(out, plaintext);
+        env.push((name.clone(), content));
     }
 
-    #[test]
+    Ok(env)
+}
+
+/// Renders a template with references to secrets, to the output file or stdout.
+pub fn render(template: &Path, output: Option<&Path>) -> eyre::Result<()> {
+    let config = crate::config();
+
+    let content = fs::read_to_string(template)
+        .wrap_err_with(|| format!("couldn't read template: {}", template.display()))?;
+
+    let rendered = render_template(config, template, &content)?;
+
+    match output {
+        Some(output) => {
+            write_rendered(output, &rendered)?;
+
+            info!(output = %output.display(), "template rendered");
+        }
+        None => stdout().write_all(rendered.as_bytes())?,
+    }
+
+    Ok(())
+}
+
+/// Writes the rendered template atomically, readable only by the owner even if the previous file
+/// wasn't.
+fn write_rendered(output: &Path, rendered: &str) -> eyre::Result<()> {
+    write_atomic(output, |file| {
+        file.set_permissions(fs::Permissions::from_mode(0o600))
+            .wrap_err("couldn't set file permissions")?;
+
+        file.write_all(rendered.as_bytes())?;
+
+        Ok(())
+    })
+}
+
+/// Replaces the secret references, failing with all the ones that couldn't be resolved.
+fn render_template(
+    config: &Config,
+    template: &Path,
+    content: &str,
+) -> eyre::Result<Zeroizing<String>> {
+    let dir = match template.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+
+    let parts = template::parse(content)
+        .wrap_err_with(|| format!("couldn't parse template: {}", template.display()))?;
+
+    let mut rendered = Zeroizing::new(String::with_capacity(content.len()));
+    let mut secrets = BTreeMap::<PathBuf, Zeroizing<String>>::new();
+    let mut unresolved = Vec::new();
+
+    for part in parts {
+        let reference = match part {
+            Part::Text(text) => {
+                rendered.push_str(text);
+
+                continue;
+            }
+            Part::Secret(reference) => reference,
+        };
+
+        let path = dir.join(&reference.file);
+
+        let content = match secrets.entry(path) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                match SecretFile::new(entry.key(), true).decrypt_to_string(config) {
+                    Ok(content) => entry.insert(content),
+                    Err(err) => {
+                        unresolved.push(format!("{reference}: {err:#}"));
+
+                        continue;
+                    }
+                }
+            }
+        };
+
+        let Some(field) = &reference.field else {
+            rendered.push_str(content.trim_end_matches(['\n', '\r']));
+
+            continue;
+        };
+
+        let value = Format::from_extension(secret_extension(&reference.file))
+            .and_then(|format| Document::parse(format, content))
+            .and_then(|document| document.get(field));
+
+        match value {
+            Ok(value) => rendered.push_str(&Zeroizing::new(value)),
+            Err(err) => unresolved.push(format!("{reference}: {err:#}")),
+        }
+    }
+
+    if !unresolved.is_empty() {
+        let err = eyre!(
+            "couldn't resolve {} secret references in {}",
+            unresolved.len(),
+            template.display()
+        );
+
+        return Err(unresolved.into_iter().fold(err, |err, msg| err.note(msg)));
+    }
+
+    Ok(rendered)
+}
+
+/// Prints the differences between two secrets, or a secret and its version at a git revision.
+pub fn diff(a: &Path, b: Option<&Path>, rev: Option<&str>, stat: bool) -> eyre::Result<()> {
+    let config = crate::config();
+
+    let (old_name, old, new_name, new) = match (rev, b) {
+        (Some(rev), None) => {
+            let raw = crate::git::show(rev, a)?;
+
+            let mut old = Zeroizing::new(Vec::new());
+            decrypt(config, &mut raw.as_slice(), &mut *old)
+                .wrap_err_with(|| format!("couldn't decrypt {} at {rev}", a.display()))?;
+
+            let mut new = Zeroizing::new(Vec::new());
+            SecretFile::new(a, true).decrypt_to(config, &mut *new)?;
+
+            (
+                format!("{rev}:{}", a.display()),
+                old,
+                a.display().to_string(),
+                new,
+            )
+        }
+        (None, Some(b)) => {
+            let mut old = Zeroizing::new(Vec::new());
+            SecretFile::new(a, true).decrypt_to(config, &mut *old)?;
+
+            let mut new = Zeroizing::new(Vec::new());
+            SecretFile::new(b, true).decrypt_to(config, &mut *new)?;
+
+            (a.display().to_string(), old, b.display().to_string(), new)
+        }
+        (Some(_), Some(_)) => bail!("pass either a second secret or a revision"),
+        (None, None) => bail!("missing the secret or revision to compare with"),
+    };
+
+    let mut stdout = stdout().lock();
+
+    if !stat {
+        let mut options = diffy::DiffOptions::new();
+        options
+            .set_original_filename(old_name)
+            .set_modified_filename(new_name);
+
+        let patch = options.create_patch_bytes(&old, &new);
+
+        if patch.hunks().is_empty() {
+            return Ok(());
+        }
+
+        let patch = Zeroizing::new(patch.to_bytes());
+
+        let patch = patch.strip_suffix(b"\n").unwrap_or(&patch);
+
+        for line in patch.split(|b| *b == b'\n') {
+            let line = String::from_utf8_lossy(line);
+
+            if line.starts_with("---") || line.starts_with("+++") {
+                writeln!(stdout, "{}", line.bold())?;
+            } else if line.starts_with("@@") {
+                writeln!(stdout, "{}", line.cyan())?;
+            } else if line.starts_with('-') {
+                writeln!(stdout, "{}", line.red())?;
+            } else if line.starts_with('+') {
+                writeln!(stdout, "{}", line.green())?;
+            } else {
+                writeln!(stdout, "{line}")?;
+            }
+        }
+
+        return Ok(());
+    }
+
+    for (change, line) in diff_stat(a, &old, &new) {
+        match change {
+            '+' => writeln!(stdout, "{}", format!("{change} {line}").green())?,
+            '-' => writeln!(stdout, "{}", format!("{change} {line}").red())?,
+            '~' => writeln!(stdout, "{}", format!("{change} {line}").yellow())?,
+            _ => writeln!(stdout, "{line}")?,
+        }
+    }
+
+    Ok(())
+}
+
+/// Summarizes the changes between two versions of a secret.
+///
+/// Structured secrets list the added (+), removed (-) and changed (~) fields, without the
+/// values. The others count the inserted and deleted lines.
+fn diff_stat(path: &Path, old: &[u8], new: &[u8]) -> Vec<(char, String)> {
+    let documents = Format::from_extension(secret_extension(path))
+        .ok()
+        .and_then(|format| {
+            let old = Document::parse(format, str::from_utf8(old).ok()?).ok()?;
+            let new = Document::parse(format, str::from_utf8(new).ok()?).ok()?;
+
+            Some((old, new))
+        });
+
+    if let Some((old, new)) = documents {
+        let old = old.fields();
+        let new = new.fields();
+
+        let keys = old.keys().chain(new.keys()).collect::<BTreeSet<_>>();
+
+        return keys
+            .into_iter()
+            .filter_map(|key| match (old.get(key), new.get(key)) {
+                (None, Some(_)) => Some(('+', key.clone())),
+                (Some(_), None) => Some(('-', key.clone())),
+                (Some(old), Some(new)) if old != new => Some(('~', key.clone())),
+                _ => None,
+            })
+            .collect();
+    }
+
+    let patch = diffy::create_patch_bytes(old, new);
+
+    let (insertions, deletions) =
+        patch
+            .hunks()
+            .iter()
+            .flat_map(|hunk| hunk.lines())
+            .fold((0, 0), |(ins, del), line| match line {
+                diffy::Line::Insert(_) => (ins + 1, del),
+                diffy::Line::Delete(_) => (ins, del + 1),
+                diffy::Line::Context(_) => (ins, del),
+            });
+
+    vec![(
+        ' ',
+        format!("{insertions} insertions(+), {deletions} deletions(-)"),
+    )]
+}
+
+/// Lists the previous versions of a secret kept locally.
+pub fn history(file: &Path) -> eyre::Result<()> {
+    let config = crate::config();
+
+    let versions = history::versions(config, file)?;
+
+    let mut stdout = stdout().lock();
+
+    if versions.is_empty() {
+        writeln!(stdout, "no previous versions of {}", file.display())?;
+
+        return Ok(());
+    }
+
+    let tz = jiff::tz::TimeZone::system();
+
+    for version in versions {
+        writeln!(
+            stdout,
+            "{:>3}  {}  {}",
+            version.number.blue(),
+            version
+                .timestamp
+                .to_zoned(tz.clone())
+                .strftime("%Y-%m-%d %H:%M:%S"),
+            version.hash
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Puts back a previous version of a secret.
+///
+/// The current version is saved in the history, so the restore can be undone too.
+pub fn restore(file: &Path, number: usize) -> eyre::Result<()> {
+    let config = crate::config();
+
+    restore_version(config, file, number)?;
+
+    info!(version = number, "secret restored");
+
+    Ok(())
+}
+
+fn restore_version(config: &Config, file: &Path, number: usize) -> eyre::Result<()> {
+    let _lock = SecretLock::acquire(config, file)?;
+
+    let versions = history::versions(config, file)?;
+
+    let version = versions
+        .iter()
+        .find(|version| version.number == number)
+        .ok_or_else(|| eyre!("no version {number} of {}", file.display()))
+        .with_note(|| {
+            format!(
+                "list the versions with {}",
+                format!("mctl secret history {}", file.display()).blue()
+            )
+        })?;
+
+    let content = version.content()?;
+    let fingerprints = version.fingerprints()?;
+
+    history::save(config, file).wrap_err("couldn't save the current version")?;
+
+    write_atomic(file, |f| f.write_all(&content).map_err(Into::into))?;
+
+    Manifest::update(config, file, fingerprints)
+}
+
+/// Lists the edits saved for recovery, or applies or discards one of them.
+pub fn recover(apply: Option<usize>, discard: Option<usize>) -> eyre::Result<()> {
+    let config = crate::config();
+
+    if let Some(number) = apply {
+        let secret = apply_recovered(config, number)?;
+
+        info!(secret = %secret.display(), "edit recovered");
+
+        return Ok(());
+    }
+
+    if let Some(number) = discard {
+        recovery::get(config, number)?.remove()?;
+
+        info!(number, "edit discarded");
+
+        return Ok(());
+    }
+
+    let recovered = recovery::list(config)?;
+
+    let mut stdout = stdout().lock();
+
+    if recovered.is_empty() {
+        writeln!(stdout, "no edits to recover")?;
+
+        return Ok(());
+    }
+
+    let tz = jiff::tz::TimeZone::system();
+
+    for recovered in recovered {
+        writeln!(
+            stdout,
+            "{:>3}  {}  {}",
+            recovered.number.blue(),
+            recovered
+                .timestamp
+                .to_zoned(tz.clone())
+                .strftime("%Y-%m-%d %H:%M:%S"),
+            recovered.secret.display()
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Encrypts a saved edit to its secret, returning the path of the secret.
+///
+/// The current version is saved in the history, so it can be restored.
+fn apply_recovered(config: &Config, number: usize) -> eyre::Result<PathBuf> {
+    let recovered = recovery::get(config, number)
+        .with_note(|| format!("list the edits with {}", "mctl secret recover".blue()))?;
+
+    let content = recovered.content(config)?;
+
+    let secret = SecretFile::new(&recovered.secret, true);
+    let _lock = secret.lock(config)?;
+
+    history::save(config, &recovered.secret).wrap_err("couldn't save the current version")?;
+
+    secret.encrypt_from(config, &mut content.as_slice())?;
+
+    recovered.remove()?;
+
+    Ok(recovered.secret)
+}
+
+/// Removes the plaintext left in the cache by the interrupted edits.
+pub fn remove_orphans() -> eyre::Result<()> {
+    recovery::sweep(crate::config())
+}
+
+pub fn rotate(paths: &[PathBuf], recursive: bool) -> eyre::Result<()> {
+    let config = crate::config();
+
+    if let [file] = paths
+        && !recursive
+        && !is_glob(file)
+    {
+        SecretFile::new(file, true).rotate(config)?;
+
+        info!("secret encrypted");
+
+        return Ok(());
+    }
+
+    let secrets = collect_secrets(paths, recursive)?;
+
+    let results = rotate_all(config, &secrets);
+
+    let mut stdout = stdout().lock();
+    let mut failed = 0;
+    for (path, res) in &results {
+        match res {
+            Ok(()) => writeln!(stdout, "{} {}", "rotated".green(), path.display())?,
+            Err(err) => {
+                failed += 1;
+
+                writeln!(stdout, "{} {}: {err:#}", "failed".red(), path.display())?;
+            }
+        }
+    }
+
+    writeln!(
+        stdout,
+        "{} secrets rotated, {} failed",
+        results.len() - failed,
+        failed
+    )?;
+
+    if failed > 0 {
+        bail!("couldn't rotate {failed} secrets");
+    }
+
+    Ok(())
+}
+
+/// Rotates the secrets in parallel, returning the result for each one.
+fn rotate_all<'a>(config: &Config, secrets: &'a [PathBuf]) -> Vec<(&'a Path, eyre::Result<()>)> {
+    let workers = std::thread::available_parallelism()
+        .map(usize::from)
+        .unwrap_or(1)
+        .min(secrets.len());
+
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new(Vec::with_capacity(secrets.len()));
+
+    std::thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| {
+                loop {
+                    let idx = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(path) = secrets.get(idx) else {
+                        break;
+                    };
+
+                    debug!(path = %path.display(), "rotating secret");
+
+                    let res = SecretFile::new(path, true).rotate(config);
+
+                    results
+                        .lock()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .push((idx, res));
+                }
+            });
+        }
+    });
+
+    let mut results = results.into_inner().unwrap_or_else(PoisonError::into_inner);
+    results.sort_unstable_by_key(|(idx, _)| *idx);
+
+    results
+        .into_iter()
+        .map(|(idx, res)| (secrets[idx].as_path(), res))
+        .collect()
+}
+
+fn is_glob(path: &Path) -> bool {
+    path.to_str()
+        .is_some_and(|path| path.contains(['*', '?', '[']))
+}
+
+/// Expands the paths to the secret files, walking directories if recursive.
+fn collect_secrets(paths: &[PathBuf], recursive: bool) -> eyre::Result<Vec<PathBuf>> {
+    let mut secrets = Vec::new();
+
+    for path in paths {
+        if is_glob(path) && !path.exists() {
+            let pattern = path.to_str().ok_or_eyre("glob pattern is not UTF-8")?;
+            let found = secrets.len();
+
+            for entry in glob::glob(pattern).wrap_err("invalid glob pattern")? {
+                let entry = entry.wrap_err("couldn't read glob entry")?;
+
+                if entry.is_dir() && recursive {
+                    find_secrets(&entry, &mut secrets)?;
+                } else if entry.is_file() {
+                    secrets.push(entry);
+                }
+            }
+
+            // Most likely a typo, that would silently skip the secrets
+            if secrets.len() == found {
+                bail!("no secrets match {pattern}");
+            }
+
+            continue;
+        }
+
+        if path.is_dir() {
+            if !recursive {
+                return Err(eyre!("{} is a directory", path.display())).note(format!(
+                    "you can pass the {} option to rotate all the secrets in it",
+                    "--recursive".blue()
+                ));
+            }
+
+            find_secrets(path, &mut secrets)?;
+
+            continue;
+        }
+
+        secrets.push(path.clone());
+    }
+
+    secrets.sort_unstable();
+    secrets.dedup();
+
+    Ok(secrets)
+}
+
+/// Recursively finds the secret files in a directory, skipping hidden entries.
+fn find_secrets(dir: &Path, secrets: &mut Vec<PathBuf>) -> eyre::Result<()> {
+    let entries = fs::read_dir(dir)
+        .wrap_err_with(|| format!("couldn't read directory: {}", dir.display()))?;
+
+    for entry in entries {
+        let entry =
+            entry.wrap_err_with(|| format!("couldn't read directory: {}", dir.display()))?;
+
+        let path = entry.path();
+
+        if entry.file_name().as_encoded_bytes().starts_with(b".") {
+            continue;
+        }
+
+        let file_type = entry.file_type()?;
+
+        if file_type.is_dir() {
+            find_secrets(&path, secrets)?;
+
+            continue;
+        }
+
+        // Filter secret files
+        if path
+            .extension()
+            .is_some_and(|ext| ext == "pem" || ext == BINARY_EXTENSION)
+            && path.is_file()
+        {
+            secrets.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+pub fn info(file: &Path) -> eyre::Result<()> {
+    let config = crate::config();
+
+    let raw = fs::read(file).wrap_err("couldn't read secret file")?;
+    let header = Header::read(&raw).wrap_err("couldn't parse the secret header")?;
+
+    let stanzas = stanzas_info(config, &header);
+
+    let mut stdout = stdout().lock();
+
+    writeln!(stdout, "{} {}", "file:".bold(), file.display())?;
+    writeln!(
+        stdout,
+        "{} {}",
+        "format:".bold(),
+        if header.armored { "armored" } else { "binary" }
+    )?;
+    writeln!(stdout, "{} {} bytes", "payload:".bold(), header.payload_len)?;
+    writeln!(stdout, "{}", "stanzas:".bold())?;
+
+    for (stanza, matched) in header.stanzas.iter().zip(&stanzas) {
+        let name = match stanza.tag.as_str() {
+            tag @ ("ssh-ed25519" | "ssh-rsa") => {
+                format!("{tag} {}", stanza.args.first().map_or("", String::as_str))
+            }
+            tag => tag.to_string(),
+        };
+
+        match matched {
+            StanzaMatch::Identity(key_file) => writeln!(
+                stdout,
+                "  {name}: {} {}",
+                "unwrapped by".green(),
+                key_file.display()
+            )?,
+            StanzaMatch::Known(labels) => writeln!(stdout, "  {name}: {}", labels.join(", "))?,
+            StanzaMatch::Unknown => writeln!(stdout, "  {name}: {}", "unknown recipient".yellow())?,
+            StanzaMatch::Grease => writeln!(stdout, "  {name}: {}", "ignored".dimmed())?,
+            StanzaMatch::UnknownType => {
+                writeln!(stdout, "  {name}: {}", "unknown stanza type".yellow())?
+            }
+        }
+    }
+
+    let decryptable = stanzas
+        .iter()
+        .any(|m| matches!(m, StanzaMatch::Identity(_)));
+
+    writeln!(
+        stdout,
+        "{} {}",
+        "decryptable:".bold(),
+        if decryptable {
+            "yes".green().to_string()
+        } else {
+            "no".red().to_string()
+        }
+    )?;
+
+    Ok(())
+}
+
+/// Recipient of a stanza, as far as it can be told from the header.
+#[derive(Debug, PartialEq)]
+enum StanzaMatch {
+    /// Can be unwrapped by an identity in the key file
+    Identity(PathBuf),
+    /// Matches the configured recipients, with where they are configured
+    Known(Vec<String>),
+    /// A supported stanza with an unknown recipient
+    Unknown,
+    /// Random stanza added by age to keep the format extensible
+    Grease,
+    UnknownType,
+}
+
+/// Matches the stanzas against the identities and the configured recipients.
+fn stanzas_info(config: &Config, header: &Header) -> Vec<StanzaMatch> {
+    let mut identities = Vec::new();
+    for key_file in config.secrets.key_files() {
+        match crate::identity::read_identity_file(key_file, &config.passphrase) {
+            Ok(keys) => identities.extend(keys.into_iter().map(|key| (key_file, key))),
+            Err(err) => warn!(error = format!("{err:#}"), "couldn't read key file"),
+        }
+    }
+
+    let mut known = Vec::new();
+    match read_recipient_lines(config.secrets.recipients_file()) {
+        Ok(lines) => known.extend(
+            lines
+                .into_iter()
+                .map(|(_, line)| ("recipients file".to_string(), line)),
+        ),
+        Err(err) => warn!(error = format!("{err:#}"), "couldn't read recipients file"),
+    }
+    for group in config.secrets.group_names() {
+        if let Ok(members) = config.secrets.group_members(group) {
+            known.extend(
+                members
+                    .into_iter()
+                    .map(|member| (format!("group {group}"), member.to_string())),
+            );
+        }
+    }
+
+    header
+        .stanzas
+        .iter()
+        .map(|stanza| {
+            let unwrapped = identities.iter().find_map(|(key_file, identity)| {
+                identity
+                    .unwrap_stanza(stanza)
+                    .is_some_and(|res| res.is_ok())
+                    .then_some(*key_file)
+            });
+
+            if let Some(key_file) = unwrapped {
+                return StanzaMatch::Identity(key_file.clone());
+            }
+
+            match stanza.tag.as_str() {
+                "ssh-ed25519" | "ssh-rsa" => {
+                    let labels = known
+                        .iter()
+                        .filter(|(_, recipient)| {
+                            ssh_recipient_tag(recipient).as_ref() == stanza.args.first()
+                        })
+                        .map(|(label, _)| label.clone())
+                        .collect::<Vec<_>>();
+
+                    if labels.is_empty() {
+                        StanzaMatch::Unknown
+                    } else {
+                        StanzaMatch::Known(labels)
+                    }
+                }
+                "X25519" | "scrypt" => StanzaMatch::Unknown,
+                tag if tag.ends_with("-grease") => StanzaMatch::Grease,
+                _ => StanzaMatch::UnknownType,
+            }
+        })
+        .collect()
+}
+
+pub fn status(dir: &Path, check: bool) -> eyre::Result<()> {
+    let config = crate::config();
+
+    let mut secrets = Vec::new();
+    find_secrets(dir, &mut secrets)?;
+    secrets.sort_unstable();
+
+    let drifts = secrets_drift(config, &secrets)?;
+
+    let mut stdout = stdout().lock();
+
+    for drift in &drifts {
+        let path = drift.path.display();
+
+        if drift.untracked {
+            writeln!(stdout, "{}: {}", path, "untracked".yellow())?;
+
+            continue;
+        }
+
+        for key in &drift.missing {
+            writeln!(stdout, "{}: {} {key}", path, "missing".red())?;
+        }
+
+        for fingerprint in &drift.extra {
+            writeln!(stdout, "{}: {} {fingerprint}", path, "extra".red())?;
+        }
+    }
+
+    if drifts.is_empty() {
+        writeln!(stdout, "{} secrets in sync", secrets.len())?;
+
+        return Ok(());
+    }
+
+    writeln!(
+        stdout,
+        "{} of {} secrets out of sync",
+        drifts.len(),
+        secrets.len()
+    )?;
+
+    if check {
+        return Err(eyre!("{} secrets out of sync", drifts.len())).note(format!(
+            "run {} to re-encrypt them",
+            "mctl secret rotate --recursive".blue()
+        ));
+    }
+
+    Ok(())
+}
+
+/// Difference between the recorded recipients of a secret and the current ones.
+#[derive(Debug)]
+struct Drift<'a> {
+    path: &'a Path,
+    /// The secret is not in the manifest
+    untracked: bool,
+    /// Public keys of the recipients the secret is not encrypted to
+    missing: Vec<String>,
+    /// Fingerprints of the recipients that shouldn't decrypt the secret anymore
+    extra: Vec<String>,
+}
+
+/// Compares the recipients in the manifests to the ones used to encrypt the secrets today.
+fn secrets_drift<'a>(config: &Config, secrets: &'a [PathBuf]) -> eyre::Result<Vec<Drift<'a>>> {
+    let mut manifests = BTreeMap::new();
+    let mut drifts = Vec::new();
+
+    for path in secrets {
+        let (dir, _) = split_secret_path(path)?;
+
+        let manifest = match manifests.entry(dir.to_path_buf()) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(Manifest::read(dir)?),
+        };
+
+        let current = rules::recipients_for(config, path)?;
+
+        let Some(recorded) = manifest.recipients(path) else {
+            drifts.push(Drift {
+                path,
+                untracked: true,
+                missing: Vec::new(),
+                extra: Vec::new(),
+            });
+
+            continue;
+        };
+
+        let missing = current
+            .iter()
+            .filter(|key| !recorded.contains(&key.fingerprint()))
+            .map(|key| key.public_key().to_string())
+            .collect::<Vec<_>>();
+
+        let current = current
+            .iter()
+            .map(RecipientKey::fingerprint)
+            .collect::<BTreeSet<_>>();
+        let extra = recorded.difference(&current).cloned().collect::<Vec<_>>();
+
+        if missing.is_empty() && extra.is_empty() {
+            continue;
+        }
+
+        drifts.push(Drift {
+            path,
+            untracked: false,
+            missing,
+            extra,
+        });
+    }
+
+    Ok(drifts)
+}
+
+/// Lists the secrets of the store, relative to its root.
+pub fn list(tree: bool) -> eyre::Result<()> {
+    let config = crate::config();
+
+    let secrets = store_secrets(config)?;
+
+    let mut stdout = stdout().lock();
+
+    if tree {
+        write!(stdout, "{}", secrets_tree(&secrets))?;
+
+        return Ok(());
+    }
+
+    for secret in secrets {
+        writeln!(stdout, "{}", secret.display())?;
+    }
+
+    Ok(())
+}
+
+/// Searches the secrets of the store, printing the matching lines only if shown.
+pub fn grep(pattern: &str, show: bool) -> eyre::Result<()> {
+    let config = crate::config();
+
+    let pattern = Regex::new(pattern).wrap_err("invalid pattern")?;
+
+    let root = config.secrets.store();
+    let secrets = store_secrets(config)?;
+
+    let identities = agent::identities(config)?;
+    let matches = grep_secrets(&identities, root, &secrets, &pattern);
+
+    let mut stdout = stdout().lock();
+
+    for found in &matches {
+        if show {
+            writeln!(
+                stdout,
+                "{}:{}: {}",
+                found.path.display().blue(),
+                found.line.green(),
+                found.content.as_str()
+            )?;
+        } else {
+            writeln!(
+                stdout,
+                "{}:{}",
+                found.path.display().blue(),
+                found.line.green()
+            )?;
+        }
+    }
+
+    if matches.is_empty() {
+        bail!("no secret matches the pattern");
+    }
+
+    Ok(())
+}
+
+/// Finds the secrets in the store, relative to its root and sorted.
+fn store_secrets(config: &Config) -> eyre::Result<Vec<PathBuf>> {
+    let root = config.secrets.store();
+
+    if !root.is_dir() {
+        return Err(eyre!("the store is not a directory: {}", root.display()))
+            .note("set the secrets.store option to the directory of the secrets");
+    }
+
+    let mut secrets = Vec::new();
+    find_secrets(root, &mut secrets)?;
+
+    let mut secrets = secrets
+        .into_iter()
+        .map(|secret| {
+            secret
+                .strip_prefix(root)
+                .map(Path::to_path_buf)
+                .unwrap_or(secret)
+        })
+        .collect::<Vec<_>>();
+
+    secrets.sort_unstable();
+
+    Ok(secrets)
+}
+
+/// Formats the sorted secrets as an indented tree of their directories.
+fn secrets_tree(secrets: &[PathBuf]) -> String {
+    let mut tree = String::new();
+    let mut prev_dirs: Vec<&OsStr> = Vec::new();
+
+    for secret in secrets {
+        let components = secret.iter().collect::<Vec<_>>();
+        let Some((file, dirs)) = components.split_last() else {
+            continue;
+        };
+
+        let common = prev_dirs
+            .iter()
+            .zip(dirs)
+            .take_while(|(prev, dir)| prev == dir)
+            .count();
+
+        for (depth, dir) in dirs.iter().enumerate().skip(common) {
+            tree.push_str(&format!("{}{}/\n", "  ".repeat(depth), dir.display()));
+        }
+
+        tree.push_str(&format!("{}{}\n", "  ".repeat(dirs.len()), file.display()));
+
+        prev_dirs = dirs.to_vec();
+    }
+
+    tree
+}
+
+/// Line of a secret matching the searched pattern.
+struct GrepMatch<'a> {
+    path: &'a Path,
+    line: usize,
+    content: Zeroizing<String>,
+}
+
+/// Decrypts the secrets in memory and returns the matching lines, skipping the secrets that can't
+/// be decrypted.
+fn grep_secrets<'a>(
+    identities: &[Box<dyn Identity>],
+    root: &Path,
+    secrets: &'a [PathBuf],
+    pattern: &Regex,
+) -> Vec<GrepMatch<'a>> {
+    let mut matches = Vec::new();
+
+    for secret in secrets {
+        let mut content = Zeroizing::new(Vec::new());
+
+        let res = File::open(root.join(secret))
+            .wrap_err("couldn't open secret file")
+            .and_then(|mut file| decrypt_with(identities, &mut file, &mut *content));
+
+        if let Err(err) = res {
+            warn!(path = %secret.display(), error = %err, "couldn't decrypt secret, skipping it");
+
+            continue;
+        }
+
+        for (idx, line) in content.split(|b| *b == b'\n').enumerate() {
+            if !pattern.is_match(line) {
+                continue;
+            }
+
+            matches.push(GrepMatch {
+                path: secret,
+                line: idx + 1,
+                content: Zeroizing::new(String::from_utf8_lossy(line).into_owned()),
+            });
+        }
+    }
+
+    matches
+}
+
+pub fn list_groups() -> eyre::Result<()> {
+    let config = crate::config();
+
+    let mut stdout = stdout().lock();
+
+    for name in config.secrets.group_names() {
+        writeln!(stdout, "{}", name.bold())?;
+
+        for member in config.secrets.group_members(name)? {
+            writeln!(stdout, "  {member}")?;
+        }
+    }
+
+    Ok(())
+}
+
+pub fn init(force: bool, add_recipient: bool) -> eyre::Result<()> {
+    let config = crate::config();
+
+    let recipient = init_identity(config, force, add_recipient)?;
+
+    writeln!(stdout(), "{recipient}")?;
+
+    Ok(())
+}
+
+/// Generates a new identity in the first key file, returning the public key.
+fn init_identity(
+    config: &Config,
+    force: bool,
+    add_recipient: bool,
+) -> eyre::Result<age::x25519::Recipient> {
+    let key_file = config
+        .secrets
+        .key_files()
+        .first()
+        .ok_or_eyre("no key file configured")?;
+
+    if key_file.try_exists()? && !force {
+        return Err(eyre!("key file already exists: {}", key_file.display())).note(format!(
+            "you can pass the {} option to overwrite it",
+            "--force".blue()
+        ));
+    }
+
+    if let Some(parent) = key_file.parent() {
+        create_private_dir(parent)?;
+    }
+
+    let identity = age::x25519::Identity::generate();
+    let recipient = identity.to_public();
+    let created = jiff::Zoned::now().strftime("%Y-%m-%dT%H:%M:%S%:z");
+
+    let content = Zeroizing::new(format!(
+        "# created: {created}\n# public key: {recipient}\n{}\n",
+        identity.to_string().expose_secret()
+    ));
+
+    SecretFile::new(key_file, false)
+        .write_atomic(|file| {
+            file.set_permissions(fs::Permissions::from_mode(0o600))?;
+            file.write_all(content.as_bytes())?;
+
+            Ok(())
+        })
+        .wrap_err("couldn't write key file")?;
+
+    info!(path = %key_file.display(), "identity created");
+
+    if add_recipient {
+        append_recipient(config.secrets.recipients_file(), &recipient, &created)?;
+    }
+
+    Ok(recipient)
+}
+
+fn create_private_dir(dir: &Path) -> eyre::Result<()> {
+    if dir.as_os_str().is_empty() || dir.is_dir() {
+        return Ok(());
+    }
+
+    debug!(path = %dir.display(), "creating key directory");
+
+    DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(dir)
+        .wrap_err_with(|| format!("couldn't create directory: {}", dir.display()))
+}
+
+fn append_recipient(
+    recipients_file: &Path,
+    recipient: &age::x25519::Recipient,
+    created: &impl std::fmt::Display,
+) -> eyre::Result<()> {
+    if let Some(parent) = recipients_file.parent() {
+        create_private_dir(parent)?;
+    }
+
+    let mut file = File::options()
+        .create(true)
+        .read(true)
+        .append(true)
+        .mode(0o600)
+        .open(recipients_file)
+        .wrap_err("couldn't open recipients file")?;
+
+    // Don't join the comment to the last recipient
+    let len = file.metadata()?.len();
+    if len > 0 {
+        let mut last = [0];
+        file.read_exact_at(&mut last, len - 1)?;
+
+        if last != *b"\n" {
+            writeln!(file)?;
+        }
+    }
+
+    writeln!(file, "# created: {created}\n{recipient}")?;
+
+    file.sync_all()?;
+
+    info!(path = %recipients_file.display(), "recipient added");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use pretty_assertions::assert_ne;
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::config::TempStrategy;
+    use crate::manifest::MANIFEST_FILE;
+
+    #[test]
+    fn encrypt_and_decrypt() {
+        let tmp = TempDir::new().unwrap();
+
+        let file = tmp.path().join("secret.txt.pem");
+
+        let plaintext = "Hello World!";
+        let mut reader = Cursor::new(plaintext);
+
+        let config = Config::mock();
+
+        SecretFile::new(&file, false)
+            .encrypt_from(&config, &mut reader)
+            .unwrap();
+
+        let mut out = Cursor::new(Vec::new());
+
+        SecretFile::new(&file, false)
+            .decrypt_to(&config, &mut out)
+            .unwrap();
+
+        let inner = out.into_inner();
+        let out = str::from_utf8(&inner).unwrap();
+
+        assert_eq!(out, plaintext);
+    }
+
+    #[test]
+    fn encrypt_and_decrypt_smaller() {
+        let tmp = TempDir::new().unwrap();
+
+        let file = tmp.path().join("secret.txt.pem");
+
+        let config = Config::mock();
+
+        let plaintext = "Hello World!";
+        let mut reader = Cursor::new(plaintext);
+
+        SecretFile::new(&file, false)
+            .encrypt_from(&config, &mut reader)
+            .unwrap();
+
+        let plaintext = "Hello";
+        let mut reader = Cursor::new(plaintext);
+
+        SecretFile::new(&file, false)
+            .encrypt_from(&config, &mut reader)
+            .unwrap();
+
+        let mut out = Cursor::new(Vec::new());
+
+        SecretFile::new(&file, false)
+            .decrypt_to(&config, &mut out)
+            .unwrap();
+
+        let inner = out.into_inner();
+        let out = str::from_utf8(&inner).unwrap();
+
+        assert_eq!(out, plaintext);
+    }
+
+    #[test]
     fn encrypt_and_decrypt_bigger() {
         let tmp = TempDir::new().unwrap();
 
-        let file = tmp.path().join("secret.txt.pem");
+        let file = tmp.path().join("secret.txt.pem");
+
+        let config = Config::mock();
+
+        let plaintext = "Hello";
+        let mut reader = Cursor::new(plaintext);
+
+        SecretFile::new(&file, false)
+            .encrypt_from(&config, &mut reader)
+            .unwrap();
+
+        let plaintext = "Hello world";
+        let mut reader = Cursor::new(plaintext);
+
+        SecretFile::new(&file, false)
+            .encrypt_from(&config, &mut reader)
+            .unwrap();
+
+        let mut out = Cursor::new(Vec::new());
+
+        SecretFile::new(&file, false)
+            .decrypt_to(&config, &mut out)
+            .unwrap();
+
+        let inner = out.into_inner();
+        let out = str::from_utf8(&inner).unwrap();
+
+        assert_eq!(out, plaintext);
+    }
+
+    #[test]
+    fn encrypt_and_decrypt_smaller_big() {
+        let tmp = TempDir::new().unwrap();
+
+        let file = tmp.path().join("secret.txt.pem");
+
+        let config = Config::mock();
+
+        let plaintext = vec![b'a'; 2048];
+        let mut reader = Cursor::new(plaintext);
+
+        SecretFile::new(&file, false)
+            .encrypt_from(&config, &mut reader)
+            .unwrap();
+
+        let plaintext = vec![b'b'; 10];
+        let mut reader = Cursor::new(plaintext.clone());
+
+        SecretFile::new(&file, false)
+            .encrypt_from(&config, &mut reader)
+            .unwrap();
+
+        let mut out = Cursor::new(Vec::new());
+
+        SecretFile::new(&file, false)
+            .decrypt_to(&config, &mut out)
+            .unwrap();
+
+        let inner = out.into_inner();
+
+        assert_eq!(inner, plaintext);
+    }
+
+    #[test]
+    fn rotate_encrypted_file() {
+        let tmp = TempDir::new().unwrap();
+
+        let file = tmp.path().join("secret.txt.pem");
+
+        let config = Config::mock();
+
+        let plaintext = b"Hello world!";
+        let mut reader = Cursor::new(plaintext);
+
+        SecretFile::new(&file, false)
+            .encrypt_from(&config, &mut reader)
+            .unwrap();
+
+        let before = fs::read_to_string(&file).unwrap();
+
+        let config = config.use_other_recipient();
+
+        SecretFile::new(&file, false).rotate(&config).unwrap();
+
+        let after = fs::read_to_string(&file).unwrap();
+
+        let mut out = Cursor::new(Vec::new());
+
+        SecretFile::new(&file, false)
+            .decrypt_to(&config, &mut out)
+            .unwrap();
+
+        let inner = out.into_inner();
+
+        assert_eq!(inner, plaintext);
+        assert_ne!(before, after);
+    }
+
+    #[test]
+    fn failed_write_keeps_previous_secret() {
+        let tmp = TempDir::new().unwrap();
+
+        let file = tmp.path().join("secret.txt.pem");
+
+        let config = Config::mock();
+
+        let plaintext = b"Hello world!";
+        let mut reader = Cursor::new(plaintext);
+
+        SecretFile::new(&file, false)
+            .encrypt_from(&config, &mut reader)
+            .unwrap();
+
+        let before = fs::read_to_string(&file).unwrap();
+
+        let res = SecretFile::new(&file, false).write_atomic(|file| {
+            file.write_all(b"-----BEGIN AGE")?;
+
+            Err(eyre!("disk full"))
+        });
+
+        assert!(res.is_err());
+
+        let after = fs::read_to_string(&file).unwrap();
+        assert_eq!(before, after);
+
+        let entries = fs::read_dir(tmp.path())
+            .unwrap()
+            .filter(|entry| entry.as_ref().unwrap().file_name() != MANIFEST_FILE)
+            .count();
+        assert_eq!(entries, 1);
+
+        let mut out = Cursor::new(Vec::new());
+
+        SecretFile::new(&file, false)
+            .decrypt_to(&config, &mut out)
+            .unwrap();
+
+        assert_eq!(out.into_inner(), plaintext);
+    }
+
+    #[test]
+    fn write_keeps_file_mode() {
+        let tmp = TempDir::new().unwrap();
+
+        let file = tmp.path().join("secret.txt.pem");
+
+        let config = Config::mock();
+
+        let mut reader = Cursor::new(b"Hello");
+
+        SecretFile::new(&file, false)
+            .encrypt_from(&config, &mut reader)
+            .unwrap();
+
+        let mode = file.metadata().unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode, 0o600);
+
+        fs::set_permissions(&file, fs::Permissions::from_mode(0o640)).unwrap();
+
+        SecretFile::new(&file, false).rotate(&config).unwrap();
+
+        let mode = file.metadata().unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode, 0o640);
+    }
+
+    fn write_private(path: &Path, content: &str) {
+        fs::write(path, content).unwrap();
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600)).unwrap();
+    }
+
+    #[test]
+    fn decrypt_with_multiple_key_files() {
+        let tmp = TempDir::new().unwrap();
+
+        let file = tmp.path().join("secret.txt.pem");
+
+        let identity = age::x25519::Identity::generate();
+
+        let key_file = tmp.path().join("key.txt");
+        write_private(
+            &key_file,
+            &format!("# other key\n{}\n", identity.to_string().expose_secret()),
+        );
+
+        let recipients_file = tmp.path().join("recipients.txt");
+        write_private(&recipients_file, &format!("{}\n", identity.to_public()));
+
+        let mock = Config::mock();
+        let config = Config::mock()
+            .with_key_files(vec![mock.secrets.key_files()[0].clone(), key_file])
+            .with_recipients_file(recipients_file);
+
+        let plaintext = b"Hello world!";
+        let mut reader = Cursor::new(plaintext);
+
+        SecretFile::new(&file, false)
+            .encrypt_from(&config, &mut reader)
+            .unwrap();
+
+        let mut out = Cursor::new(Vec::new());
+
+        SecretFile::new(&file, false)
+            .decrypt_to(&config, &mut out)
+            .unwrap();
+
+        assert_eq!(out.into_inner(), plaintext);
+
+        let err = SecretFile::new(&file, false)
+            .decrypt_to(&mock, &mut Cursor::new(Vec::new()))
+            .unwrap_err();
+
+        assert!(matches!(
+            err.downcast_ref::<DecryptError>(),
+            Some(DecryptError::NoMatchingKeys)
+        ));
+    }
+
+    #[test]
+    fn encrypt_and_decrypt_ssh_key() {
+        let tmp = TempDir::new().unwrap();
+
+        let file = tmp.path().join("secret.txt.pem");
+
+        let config = Config::mock().use_ssh_key();
+
+        let plaintext = b"Hello world!";
+        let mut reader = Cursor::new(plaintext);
+
+        SecretFile::new(&file, false)
+            .encrypt_from(&config, &mut reader)
+            .unwrap();
+
+        let mut out = Cursor::new(Vec::new());
+
+        SecretFile::new(&file, false)
+            .decrypt_to(&config, &mut out)
+            .unwrap();
+
+        assert_eq!(out.into_inner(), plaintext);
+    }
+
+    #[test]
+    fn decrypt_with_passphrase_protected_key_file() {
+        let tmp = TempDir::new().unwrap();
+
+        let file = tmp.path().join("secret.txt.pem");
+
+        let identity = age::x25519::Identity::generate();
+
+        let mut recipient = age::scrypt::Recipient::new("test-passphrase".into());
+        recipient.set_work_factor(2);
+
+        let key_file = tmp.path().join("key.txt.age");
+        let mut encrypted = Vec::new();
+        let mut writer =
+            age::Encryptor::with_recipients(std::iter::once(&recipient as &dyn age::Recipient))
+                .unwrap()
+                .wrap_output(
+                    ArmoredWriter::wrap_output(&mut encrypted, age::armor::Format::AsciiArmor)
+                        .unwrap(),
+                )
+                .unwrap();
+        writer
+            .write_all(identity.to_string().expose_secret().as_bytes())
+            .unwrap();
+        writer.finish().and_then(|armor| armor.finish()).unwrap();
+        fs::write(&key_file, &encrypted).unwrap();
+        fs::set_permissions(&key_file, fs::Permissions::from_mode(0o600)).unwrap();
+
+        let recipients_file = tmp.path().join("recipients.txt");
+        write_private(&recipients_file, &format!("{}\n", identity.to_public()));
+
+        let config = Config::mock()
+            .with_passphrase_command("echo test-passphrase")
+            .with_key_files(vec![key_file])
+            .with_recipients_file(recipients_file);
+
+        let plaintext = b"Hello world!";
+        let mut reader = Cursor::new(plaintext);
+
+        SecretFile::new(&file, false)
+            .encrypt_from(&config, &mut reader)
+            .unwrap();
+
+        let mut out = Cursor::new(Vec::new());
+
+        SecretFile::new(&file, false)
+            .decrypt_to(&config, &mut out)
+            .unwrap();
+
+        assert_eq!(out.into_inner(), plaintext);
+    }
+
+    #[test]
+    fn init_identity_files() {
+        let tmp = TempDir::new().unwrap();
+
+        let config = Config::mock().in_dir(tmp.path());
+
+        let recipient = init_identity(&config, false, true).unwrap();
+
+        let key_file = tmp.path().join("age/key.txt");
+        let mode = key_file.metadata().unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode, 0o600);
+
+        let dir_mode = tmp
+            .path()
+            .join("age")
+            .metadata()
+            .unwrap()
+            .permissions()
+            .mode()
+            & 0o777;
+        assert_eq!(dir_mode, 0o700);
+
+        let recipients = fs::read_to_string(config.secrets.recipients_file()).unwrap();
+        assert!(recipients.contains(&recipient.to_string()));
+
+        let before = fs::read_to_string(&key_file).unwrap();
+
+        assert!(init_identity(&config, false, false).is_err());
+
+        assert_eq!(before, fs::read_to_string(&key_file).unwrap());
+
+        init_identity(&config, true, false).unwrap();
+
+        assert_ne!(before, fs::read_to_string(&key_file).unwrap());
+
+        let config = Config::mock().in_dir(tmp.path());
+        let identities = config.identities().unwrap();
+        assert_eq!(identities.len(), 1);
+
+        // Appends after a recipient without a trailing new line
+        let recipients_file = config.secrets.recipients_file();
+        fs::write(recipients_file, recipients.trim_end()).unwrap();
+
+        let other = age::x25519::Identity::generate().to_public();
+        append_recipient(recipients_file, &other, &"now").unwrap();
+
+        let recipients = read_recipient_lines(recipients_file).unwrap();
+        assert_eq!(recipients.len(), 2);
+        assert_eq!(recipients[1].1, other.to_string());
+    }
+
+    #[test]
+    fn rotate_secrets_recursively() {
+        let tmp = TempDir::new().unwrap();
+
+        let config = Config::mock();
+
+        let nested = tmp.path().join("prod/db");
+        fs::create_dir_all(&nested).unwrap();
+        fs::create_dir_all(tmp.path().join(".git")).unwrap();
+
+        let files = [
+            tmp.path().join("a.txt.pem"),
+            tmp.path().join("prod/b.pem"),
+            nested.join("c.toml.pem"),
+            tmp.path().join(".git/d.pem"),
+        ];
+
+        for file in &files {
+            SecretFile::new(file, false)
+                .encrypt_from(&config, &mut Cursor::new(b"Hello"))
+                .unwrap();
+        }
+
+        fs::write(tmp.path().join("prod/broken.pem"), "not a secret").unwrap();
+        fs::write(tmp.path().join("prod/plain.txt"), "not a secret").unwrap();
+
+        let secrets = collect_secrets(&[tmp.path().to_path_buf()], true).unwrap();
+
+        assert_eq!(
+            secrets,
+            [
+                files[0].clone(),
+                tmp.path().join("prod/b.pem"),
+                tmp.path().join("prod/broken.pem"),
+                files[2].clone(),
+            ]
+        );
+
+        assert!(collect_secrets(&[tmp.path().to_path_buf()], false).is_err());
+
+        let before = fs::read_to_string(&files[0]).unwrap();
+
+        let config = config.use_other_recipient();
+
+        let results = rotate_all(&config, &secrets);
+
+        let failed = results
+            .iter()
+            .filter(|(_, res)| res.is_err())
+            .map(|(path, _)| *path)
+            .collect::<Vec<_>>();
+
+        assert_eq!(failed, [tmp.path().join("prod/broken.pem")]);
+        assert_ne!(before, fs::read_to_string(&files[0]).unwrap());
+
+        for file in &files[..3] {
+            let mut out = Cursor::new(Vec::new());
+
+            SecretFile::new(file, false)
+                .decrypt_to(&config, &mut out)
+                .unwrap();
+
+            assert_eq!(out.into_inner(), b"Hello");
+        }
+    }
+
+    #[test]
+    fn collect_secrets_with_glob() {
+        let tmp = TempDir::new().unwrap();
+
+        fs::create_dir_all(tmp.path().join("prod")).unwrap();
+        fs::write(tmp.path().join("prod/a.pem"), "").unwrap();
+        fs::write(tmp.path().join("prod/b.pem"), "").unwrap();
+        fs::write(tmp.path().join("prod/c.txt"), "").unwrap();
+
+        let pattern = tmp.path().join("*/*.pem");
+
+        let secrets = collect_secrets(&[pattern], false).unwrap();
+
+        assert_eq!(
+            secrets,
+            [tmp.path().join("prod/a.pem"), tmp.path().join("prod/b.pem")]
+        );
+
+        let typo = tmp.path().join("*/*.pme");
+        assert!(collect_secrets(&[typo], false).is_err());
+    }
+
+    #[test]
+    fn encrypt_with_directory_rules() {
+        let tmp = TempDir::new().unwrap();
+
+        let identity = age::x25519::Identity::generate();
+
+        let key_file = tmp.path().join("key.txt");
+        write_private(
+            &key_file,
+            &format!("{}\n", identity.to_string().expose_secret()),
+        );
+
+        fs::create_dir_all(tmp.path().join("store/prod/db")).unwrap();
+        fs::write(
+            tmp.path().join("store/.mctl.toml"),
+            format!(
+                r#"
+[[rules]]
+path = "prod/**"
+recipients = ["{}"]
+"#,
+                identity.to_public()
+            ),
+        )
+        .unwrap();
+
+        let mock = Config::mock();
+        let prod = Config::mock().with_key_files(vec![key_file]);
+
+        let global_secret = tmp.path().join("store/global.pem");
+        let prod_secret = tmp.path().join("store/prod/db/password.pem");
+
+        for file in [&global_secret, &prod_secret] {
+            SecretFile::new(file, false)
+                .encrypt_from(&mock, &mut Cursor::new(b"Hello"))
+                .unwrap();
+        }
+
+        let mut out = Cursor::new(Vec::new());
+        SecretFile::new(&global_secret, false)
+            .decrypt_to(&mock, &mut out)
+            .unwrap();
+        assert_eq!(out.into_inner(), b"Hello");
+
+        assert!(
+            SecretFile::new(&prod_secret, false)
+                .decrypt_to(&mock, &mut Cursor::new(Vec::new()))
+                .is_err()
+        );
+
+        let mut out = Cursor::new(Vec::new());
+        SecretFile::new(&prod_secret, false)
+            .decrypt_to(&prod, &mut out)
+            .unwrap();
+        assert_eq!(out.into_inner(), b"Hello");
+    }
+
+    #[test]
+    fn binary_and_compressed_secrets() {
+        let tmp = TempDir::new().unwrap();
 
         let config = Config::mock();
+        let recipient = config.secrets.recipients().unwrap()[0]
+            .public_key()
+            .to_string();
+
+        fs::create_dir_all(tmp.path().join("dumps")).unwrap();
+        fs::write(
+            tmp.path().join(".mctl.toml"),
+            format!(
+                r#"
+[[rules]]
+path = "dumps/*"
+recipients = ["{recipient}"]
+format = "binary"
+compress = true
+"#
+            ),
+        )
+        .unwrap();
+
+        let plaintext = "INSERT INTO users VALUES (1, 'admin');\n".repeat(100);
+
+        let armored = tmp.path().join("dump.sql.pem");
+        let binary = tmp.path().join("dump.sql.age");
+        let compressed = tmp.path().join("dumps/dump.sql.pem");
+
+        for file in [&armored, &binary, &compressed] {
+            SecretFile::new(file, false)
+                .encrypt_from(&config, &mut plaintext.as_bytes())
+                .unwrap();
+
+            let mut out = Vec::new();
+            SecretFile::new(file, false)
+                .decrypt_to(&config, &mut out)
+                .unwrap();
+            assert_eq!(String::from_utf8(out).unwrap(), plaintext);
+        }
 
-        let plaintext = "Hello";
-        let mut reader = Cursor::new(plaintext);
+        let raw = fs::read(&binary).unwrap();
+        assert!(raw.starts_with(b"age-encryption.org/v1\n"));
+        assert!(!Header::read(&raw).unwrap().armored);
+
+        let raw = fs::read(&compressed).unwrap();
+        assert!(!Header::read(&raw).unwrap().armored);
+        assert!(raw.len() < fs::read(&binary).unwrap().len());
+
+        assert_eq!(secret_extension(&binary), Some("sql"));
+    }
+
+    fn stanzas_info_without_grease(config: &Config, header: &Header) -> Vec<StanzaMatch> {
+        stanzas_info(config, header)
+            .into_iter()
+            .filter(|m| *m != StanzaMatch::Grease)
+            .collect()
+    }
+
+    #[test]
+    fn secret_stanzas_info() {
+        let tmp = TempDir::new().unwrap();
+
+        let file = tmp.path().join("secret.txt.pem");
+
+        let config = Config::mock();
 
         SecretFile::new(&file, false)
-            .encrypt_from(&config, &mut reader)
+            .encrypt_from(&config, &mut Cursor::new(b"Hello"))
             .unwrap();
 
-        let plaintext = "Hello world";
-        let mut reader = Cursor::new(plaintext);
+        let raw = fs::read(&file).unwrap();
+        let header = Header::read(&raw).unwrap();
+
+        assert!(header.armored);
+
+        let stanzas = stanzas_info_without_grease(&config, &header);
+        assert_eq!(
+            stanzas,
+            [StanzaMatch::Identity(config.secrets.key_files()[0].clone())]
+        );
 
+        let other = Config::mock().use_other_recipient();
         SecretFile::new(&file, false)
-            .encrypt_from(&config, &mut reader)
+            .encrypt_from(&other, &mut Cursor::new(b"Hello"))
             .unwrap();
 
-        let mut out = Cursor::new(Vec::new());
+        let raw = fs::read(&file).unwrap();
+        let header = Header::read(&raw).unwrap();
+
+        let stanzas = stanzas_info_without_grease(&Config::mock().use_ssh_key(), &header);
+        assert_eq!(stanzas, [StanzaMatch::Unknown, StanzaMatch::Unknown]);
 
+        let ssh = Config::mock().use_ssh_key();
         SecretFile::new(&file, false)
-            .decrypt_to(&config, &mut out)
+            .encrypt_from(&ssh, &mut Cursor::new(b"Hello"))
             .unwrap();
 
-        let inner = out.into_inner();
-        let out = str::from_utf8(&inner).unwrap();
+        let raw = fs::read(&file).unwrap();
+        let header = Header::read(&raw).unwrap();
 
-        assert_eq!(out, plaintext);
+        let stanzas = stanzas_info_without_grease(&Config::mock(), &header);
+        assert_eq!(stanzas, [StanzaMatch::Unknown]);
+
+        let known = Config::mock().with_recipients_file(
+            PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("assets/test.recipients.ssh.txt"),
+        );
+        let stanzas = stanzas_info_without_grease(&known, &header);
+        assert_eq!(
+            stanzas,
+            [StanzaMatch::Known(vec!["recipients file".to_string()])]
+        );
+
+        let stanzas = stanzas_info_without_grease(&ssh, &header);
+        assert_eq!(
+            stanzas,
+            [StanzaMatch::Identity(ssh.secrets.key_files()[0].clone())]
+        );
     }
 
     #[test]
-    fn encrypt_and_decrypt_smaller_big() {
+    fn secrets_out_of_sync() {
         let tmp = TempDir::new().unwrap();
 
-        let file = tmp.path().join("secret.txt.pem");
+        let config = Config::mock();
+
+        fs::create_dir_all(tmp.path().join("prod")).unwrap();
+
+        let files = [tmp.path().join("a.pem"), tmp.path().join("prod/b.pem")];
+
+        for file in &files {
+            SecretFile::new(file, false)
+                .encrypt_from(&config, &mut Cursor::new(b"Hello"))
+                .unwrap();
+        }
+
+        let untracked = tmp.path().join("prod/c.pem");
+        fs::copy(&files[1], &untracked).unwrap();
+
+        let mut secrets = Vec::new();
+        find_secrets(tmp.path(), &mut secrets).unwrap();
+        secrets.sort_unstable();
+
+        let drifts = secrets_drift(&config, &secrets).unwrap();
+        assert_eq!(drifts.len(), 1);
+        assert_eq!(drifts[0].path, untracked);
+        assert!(drifts[0].untracked);
+
+        fs::remove_file(&untracked).unwrap();
+        secrets.retain(|path| *path != untracked);
+
+        let other = Config::mock().use_other_recipient();
+
+        let drifts = secrets_drift(&other, &secrets).unwrap();
+        assert_eq!(drifts.len(), 2);
+        assert_eq!(
+            drifts[0].missing,
+            ["age18kpe8fr5va7n837kxc3v2dpsh73gptt6wm3xsguj45l9xksph56q55jegd"]
+        );
+        assert!(drifts[0].extra.is_empty());
+
+        let ssh = Config::mock().use_ssh_key();
+
+        let drifts = secrets_drift(&ssh, &secrets).unwrap();
+        assert_eq!(drifts.len(), 2);
+        assert_eq!(drifts[1].missing.len(), 1);
+        assert_eq!(
+            drifts[1].extra,
+            [crate::config::fingerprint(
+                "age1zt4juc2eds5w7jc5rjfnfs9l9zpq6awmfup2ypzthg4km7f8a3lqsvh5kt"
+            )]
+        );
+
+        let results = rotate_all(&other, &secrets);
+        assert!(results.iter().all(|(_, res)| res.is_ok()));
+
+        let drifts = secrets_drift(&other, &secrets).unwrap();
+        assert!(drifts.is_empty());
+    }
+
+    #[test]
+    fn set_structured_secret_field() {
+        let tmp = TempDir::new().unwrap();
 
         let config = Config::mock();
 
-        let plaintext = vec![b'a'; 2048];
-        let mut reader = Cursor::new(plaintext);
+        let file = tmp.path().join("db.toml.pem");
+        let secret = SecretFile::new(&file, false);
 
-        SecretFile::new(&file, false)
-            .encrypt_from(&config, &mut reader)
+        secret
+            .encrypt_from(
+                &config,
+                &mut Cursor::new("[db]\npassword = \"old\" # rotated\n"),
+            )
             .unwrap();
 
-        let plaintext = vec![b'b'; 10];
-        let mut reader = Cursor::new(plaintext.clone());
+        set_field(&config, &secret, "db.password", "new").unwrap();
 
-        SecretFile::new(&file, false)
-            .encrypt_from(&config, &mut reader)
+        let document = secret.read_document(&config).unwrap();
+        assert_eq!(document.get("db.password").unwrap(), "new");
+
+        let mut out = Vec::new();
+        secret.decrypt_to(&config, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "[db]\npassword = \"new\" # rotated\n"
+        );
+
+        let plain = tmp.path().join("db.pem");
+        let secret = SecretFile::new(&plain, false);
+        secret
+            .encrypt_from(&config, &mut Cursor::new("password"))
             .unwrap();
 
-        let mut out = Cursor::new(Vec::new());
+        assert!(set_field(&config, &secret, "db.password", "new").is_err());
+    }
 
-        SecretFile::new(&file, false)
-            .decrypt_to(&config, &mut out)
+    #[test]
+    fn secrets_as_environment() {
+        let tmp = TempDir::new().unwrap();
+
+        let config = Config::mock();
+
+        let pass = tmp.path().join("pass.pem");
+        SecretFile::new(&pass, false)
+            .encrypt_from(&config, &mut Cursor::new("hunter2\n"))
             .unwrap();
 
-        let inner = out.into_inner();
+        let env_file = tmp.path().join("app.env.pem");
+        SecretFile::new(&env_file, false)
+            .encrypt_from(
+                &config,
+                &mut Cursor::new("# app\nUSER=admin\nDB_PASS='from file'\n"),
+            )
+            .unwrap();
 
-        assert_eq!(inner, plaintext);
+        let env = secrets_env(
+            &config,
+            &[("DB_PASS".to_string(), pass)],
+            std::slice::from_ref(&env_file),
+        )
+        .unwrap();
+
+        let env = env
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            env,
+            [
+                ("USER", "admin"),
+                ("DB_PASS", "from file"),
+                ("DB_PASS", "hunter2")
+            ]
+        );
     }
 
     #[test]
-    fn rotate_encrypted_file() {
+    fn render_template_with_secrets() {
         let tmp = TempDir::new().unwrap();
 
-        let file = tmp.path().join("secret.txt.pem");
+        let config = Config::mock();
+
+        fs::create_dir(tmp.path().join("secrets")).unwrap();
+
+        SecretFile::new(&tmp.path().join("secrets/db.toml.pem"), false)
+            .encrypt_from(&config, &mut Cursor::new("[db]\npassword = \"hunter2\"\n"))
+            .unwrap();
+        SecretFile::new(&tmp.path().join("secrets/token.pem"), false)
+            .encrypt_from(&config, &mut Cursor::new("abc123\n"))
+            .unwrap();
+
+        let template = tmp.path().join("app.conf.tmpl");
+
+        let content = r#"password = "{{ secret "secrets/db.toml.pem" "db.password" }}"
+token = "{{ secret "secrets/token.pem" }}"
+"#;
+
+        let rendered = render_template(&config, &template, content).unwrap();
+        assert_eq!(
+            rendered.as_str(),
+            "password = \"hunter2\"\ntoken = \"abc123\"\n"
+        );
+
+        let content = r#"{{ secret "secrets/db.toml.pem" "db.user" }}
+{{ secret "secrets/missing.pem" }}
+{{ secret "secrets/token.pem" }}
+{{ secret "secrets/token.pem" "field" }}
+"#;
+
+        let err = render_template(&config, &template, content).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "couldn't resolve 3 secret references in {}",
+                template.display()
+            )
+        );
+
+        // Replaces an existing file readable by others
+        let output = tmp.path().join("app.conf");
+        fs::write(&output, "old").unwrap();
+        fs::set_permissions(&output, fs::Permissions::from_mode(0o644)).unwrap();
+
+        write_rendered(&output, "password = \"hunter2\"\n").unwrap();
+
+        assert_eq!(
+            fs::read_to_string(&output).unwrap(),
+            "password = \"hunter2\"\n"
+        );
+        assert_eq!(
+            fs::metadata(&output).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+    }
+
+    #[test]
+    fn diff_stat_of_secrets() {
+        let old = b"[db]\nuser = \"admin\"\npassword = \"old\"\n\n[[hosts]]\nname = \"a\"\n";
+        let new = b"[db]\npassword = \"new\"\nport = 5432\n\n[[hosts]]\nname = \"a\"\n";
+
+        let stat = diff_stat(Path::new("db.toml.pem"), old, new);
+        assert_eq!(
+            stat,
+            [
+                ('~', "db.password".to_string()),
+                ('+', "db.port".to_string()),
+                ('-', "db.user".to_string()),
+            ]
+        );
+
+        let stat = diff_stat(Path::new("db.pem"), old, new);
+        assert_eq!(stat, [(' ', "2 insertions(+), 2 deletions(-)".to_string())]);
+    }
+
+    #[test]
+    fn restore_previous_versions() {
+        let tmp = TempDir::new().unwrap();
+
+        let mut config = Config::mock().with_history_dir(&tmp.path().join("history"));
+        config.history.keep = 2;
+
+        let file = tmp.path().join("secret.pem");
+        let secret = SecretFile::new(&file, false);
+
+        secret
+            .encrypt_from(&config, &mut Cursor::new("one"))
+            .unwrap();
+
+        for content in ["two", "three", "four"] {
+            // Versions are ordered by millisecond
+            std::thread::sleep(std::time::Duration::from_millis(2));
+
+            let tmp = secret.decrypt_to_tmp(&config).unwrap();
+            fs::write(&tmp.path, content).unwrap();
+            secret.encrypt_from_tmp(&config, tmp).unwrap();
+        }
+
+        let versions = history::versions(&config, &file).unwrap();
+        assert_eq!(versions.len(), 2);
+        assert_eq!(versions[0].number, 1);
+
+        let fingerprints = versions[0].fingerprints().unwrap().unwrap();
+        assert_eq!(fingerprints.len(), 1);
+
+        std::thread::sleep(std::time::Duration::from_millis(2));
+
+        restore_version(&config, &file, 2).unwrap();
+
+        let mut out = Vec::new();
+        secret.decrypt_to(&config, &mut out).unwrap();
+        assert_eq!(out, b"two");
+
+        // The restored version is saved too
+        let versions = history::versions(&config, &file).unwrap();
+        assert_eq!(versions.len(), 2);
+
+        let mut out = Vec::new();
+        decrypt(
+            &config,
+            &mut versions[0].content().unwrap().as_slice(),
+            &mut out,
+        )
+        .unwrap();
+        assert_eq!(out, b"four");
+
+        assert!(restore_version(&config, &file, 3).is_err());
+    }
+
+    #[test]
+    fn lock_secret_while_editing() {
+        let tmp = TempDir::new().unwrap();
 
         let config = Config::mock();
 
-        let plaintext = b"Hello world!";
-        let mut reader = Cursor::new(plaintext);
+        let file = tmp.path().join("secret.pem");
+        let secret = SecretFile::new(&file, false);
 
-        SecretFile::new(&file, false)
-            .encrypt_from(&config, &mut reader)
+        let lock = secret.lock(&config).unwrap();
+        assert!(secret.lock(&config).is_err());
+
+        drop(lock);
+        secret.lock(&config).unwrap();
+    }
+
+    #[test]
+    fn recover_concurrent_change() {
+        let tmp = TempDir::new().unwrap();
+
+        let config = Config::mock()
+            .with_cache_dir(&tmp.path().join("cache"))
+            .with_history_dir(&tmp.path().join("history"));
+
+        let file = tmp.path().join("secret.pem");
+        let secret = SecretFile::new(&file, false);
+
+        secret
+            .encrypt_from(&config, &mut Cursor::new("one"))
             .unwrap();
 
-        let before = fs::read_to_string(&file).unwrap();
+        let tmp_file = secret.decrypt_to_tmp(&config).unwrap();
+        let tmp_path = tmp_file.path.clone();
+        fs::write(&tmp_path, "mine").unwrap();
 
-        let config = config.use_other_recipient();
+        // Changed by another process
+        secret
+            .encrypt_from(&config, &mut Cursor::new("theirs"))
+            .unwrap();
 
-        SecretFile::new(&file, false).rotate(&config).unwrap();
+        assert!(secret.encrypt_from_tmp(&config, tmp_file).is_err());
+        assert!(!tmp_path.exists());
 
-        let after = fs::read_to_string(&file).unwrap();
+        let mut out = Vec::new();
+        secret.decrypt_to(&config, &mut out).unwrap();
+        assert_eq!(out, b"theirs");
 
-        let mut out = Cursor::new(Vec::new());
+        let recovered = recovery::list(&config).unwrap();
+        assert_eq!(recovered.len(), 1);
+        assert_eq!(
+            recovered[0].secret,
+            rules::absolute_secret_path(&file).unwrap()
+        );
 
-        SecretFile::new(&file, false)
-            .decrypt_to(&config, &mut out)
+        apply_recovered(&config, 1).unwrap();
+
+        let mut out = Vec::new();
+        secret.decrypt_to(&config, &mut out).unwrap();
+        assert_eq!(out, b"mine");
+
+        assert!(recovery::list(&config).unwrap().is_empty());
+        assert!(apply_recovered(&config, 1).is_err());
+
+        // The overwritten secret can be restored
+        restore_version(&config, &file, 1).unwrap();
+
+        let mut out = Vec::new();
+        secret.decrypt_to(&config, &mut out).unwrap();
+        assert_eq!(out, b"theirs");
+    }
+
+    #[test]
+    fn edit_in_memory_file() {
+        let tmp = TempDir::new().unwrap();
+
+        let config = Config::mock()
+            .with_temp(TempStrategy::Memfd)
+            .with_history_dir(&tmp.path().join("history"));
+
+        let file = tmp.path().join("secret.toml.pem");
+        let secret = SecretFile::new(&file, false);
+
+        secret
+            .encrypt_from(&config, &mut Cursor::new("one"))
             .unwrap();
 
-        let inner = out.into_inner();
+        let tmp_file = secret.decrypt_to_tmp(&config).unwrap();
+        assert!(tmp_file.path.starts_with("/proc/self/fd"));
+        assert_eq!(fs::read_to_string(&tmp_file.path).unwrap(), "one");
 
-        assert_eq!(inner, plaintext);
-        assert_ne!(before, after);
+        assert!(tmp_file.unchanged());
+
+        // The editor gets the memory file even if it's closed on exec
+        let editor = tmp.path().join("editor.sh");
+        fs::write(&editor, "#!/bin/sh\nprintf two > \"$1\"\n").unwrap();
+        fs::set_permissions(&editor, fs::Permissions::from_mode(0o700)).unwrap();
+
+        let status = tmp_file
+            .spawn_editor(editor.to_str().unwrap())
+            .unwrap()
+            .wait()
+            .unwrap();
+        assert!(status.success());
+        assert!(!tmp_file.unchanged());
+
+        secret.encrypt_from_tmp(&config, tmp_file).unwrap();
+
+        let mut out = Vec::new();
+        secret.decrypt_to(&config, &mut out).unwrap();
+        assert_eq!(out, b"two");
+    }
+
+    #[test]
+    fn edit_archive_as_directory() {
+        let tmp = TempDir::new().unwrap();
+
+        let mut config = Config::mock()
+            .with_temp(TempStrategy::Cache)
+            .with_cache_dir(&tmp.path().join("cache"))
+            .with_history_dir(&tmp.path().join("history"));
+        fs::create_dir_all(tmp.path().join("cache")).unwrap();
+
+        let pki = tmp.path().join("pki");
+        fs::create_dir_all(pki.join("private")).unwrap();
+        fs::write(pki.join("ca.crt"), "certificate").unwrap();
+        fs::write(pki.join("private/ca.key"), "key").unwrap();
+        fs::set_permissions(pki.join("private"), fs::Permissions::from_mode(0o700)).unwrap();
+
+        let file = tmp.path().join("pki.tar.age");
+        let secret = SecretFile::new(&file, false);
+        assert!(secret.is_archive());
+
+        secret
+            .encrypt_stream(&config, |plaintext| archive::pack(&pki, plaintext))
+            .unwrap();
+
+        // Changes the extracted directory
+        let editor = tmp.path().join("editor.sh");
+        fs::write(
+            &editor,
+            "#!/bin/sh\ntest -f \"$1/private/ca.key\" && echo renewed > \"$1/ca.crt\"\n",
+        )
+        .unwrap();
+        fs::set_permissions(&editor, fs::Permissions::from_mode(0o755)).unwrap();
+        config.editor = editor.to_string_lossy().into_owned();
+
+        edit_archive(&config, &secret).unwrap();
+
+        let out = tmp.path().join("out");
+        decrypt_stream(&config, &mut secret.open().unwrap(), |plaintext| {
+            archive::unpack(plaintext, &out)
+        })
+        .unwrap();
+
+        assert_eq!(fs::read_to_string(out.join("ca.crt")).unwrap(), "renewed\n");
+        assert_eq!(
+            fs::read_to_string(out.join("private/ca.key")).unwrap(),
+            "key"
+        );
+        assert_eq!(
+            fs::metadata(out.join("private"))
+                .unwrap()
+                .permissions()
+                .mode()
+                & 0o777,
+            0o700
+        );
+        assert_eq!(history::versions(&config, &file).unwrap().len(), 1);
+
+        // The extracted directory is removed
+        let cache = fs::read_dir(tmp.path().join("cache"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect::<Vec<_>>();
+        assert!(
+            cache
+                .iter()
+                .all(|name| !name.to_string_lossy().ends_with(".tar"))
+        );
+
+        // A failed edit is saved for recovery
+        fs::write(&editor, "#!/bin/sh\nrm \"$1/ca.crt\"\nexit 1\n").unwrap();
+        config.editor = editor.to_string_lossy().into_owned();
+
+        assert!(edit_archive(&config, &secret).is_err());
+        assert_eq!(recovery::list(&config).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn list_and_grep_store() {
+        let tmp = TempDir::new().unwrap();
+
+        let config = Config::mock().with_store(tmp.path());
+
+        fs::create_dir_all(tmp.path().join("prod/db")).unwrap();
+        fs::create_dir_all(tmp.path().join(".git")).unwrap();
+
+        for (file, content) in [
+            ("token.pem", "ghp_token\n"),
+            ("prod/api.env.age", "USER=admin\nPASSWORD=hunter2\n"),
+            (
+                "prod/db/main.toml.pem",
+                "user = \"admin\"\npassword = \"secret\"\n",
+            ),
+        ] {
+            SecretFile::new(&tmp.path().join(file), false)
+                .encrypt_from(&config, &mut content.as_bytes())
+                .unwrap();
+        }
+
+        // Skipped: not a secret, hidden, and encrypted to someone else
+        fs::write(tmp.path().join("README.md"), "docs").unwrap();
+        fs::write(tmp.path().join(".git/config.pem"), "hidden").unwrap();
+        let other = age::x25519::Identity::generate().to_public().to_string();
+        let other = crate::config::parse_recipient(&other).unwrap();
+        let mut foreign = File::create(tmp.path().join("prod/foreign.pem")).unwrap();
+        encrypt(&[other], &mut "USER=admin".as_bytes(), &mut foreign).unwrap();
+
+        let secrets = store_secrets(&config).unwrap();
+        assert_eq!(
+            secrets,
+            [
+                "prod/api.env.age",
+                "prod/db/main.toml.pem",
+                "prod/foreign.pem",
+                "token.pem"
+            ]
+            .map(PathBuf::from)
+        );
+
+        assert_eq!(
+            secrets_tree(&secrets),
+            "prod/\n  api.env.age\n  db/\n    main.toml.pem\n  foreign.pem\ntoken.pem\n"
+        );
+
+        let identities = config.identities().unwrap();
+        let pattern = Regex::new("(?i)admin").unwrap();
+        let matches = grep_secrets(&identities, tmp.path(), &secrets, &pattern)
+            .into_iter()
+            .map(|found| {
+                (
+                    found.path.to_path_buf(),
+                    found.line,
+                    found.content.to_string(),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            matches,
+            [
+                (
+                    PathBuf::from("prod/api.env.age"),
+                    1,
+                    "USER=admin".to_string()
+                ),
+                (
+                    PathBuf::from("prod/db/main.toml.pem"),
+                    1,
+                    "user = \"admin\"".to_string()
+                ),
+            ]
+        );
     }
 }
@@ -0,0 +1,320 @@
+//! Library interface to read and write the secrets, without the global configuration.
+//!
+//! ```no_run
+//! use std::path::Path;
+//!
+//! use mctl::{config::Config, store::SecretStore};
+//!
+//! # fn main() -> eyre::Result<()> {
+//! let store = SecretStore::from_config(Config::read(None)?);
+//!
+//! let token = store.read(Path::new("secrets/token.pem"))?;
+//!
+//! store.edit_with(Path::new("secrets/db.env.pem"), |content| {
+//!     content.extend_from_slice(b"PORT=5432\n");
+//!
+//!     Ok(())
+//! })?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{
+    io::{Read, Write},
+    path::Path,
+};
+
+use age::Identity;
+use eyre::WrapErr;
+use tracing::debug;
+use zeroize::Zeroizing;
+
+use crate::{
+    config::{Config, RecipientKey, parse_recipient},
+    encoding::Encoding,
+    history,
+    lock::SecretLock,
+    manifest::Manifest,
+    rules,
+    secret::{decrypt, decrypt_with, encrypt, encrypt_with},
+    util::write_atomic,
+};
+
+/// Reads and writes secrets with the keys of a configuration or explicit ones.
+pub struct SecretStore {
+    keys: Keys,
+}
+
+enum Keys {
+    /// Uses the key files, the recipient rules, the manifests, the history and the agent.
+    Config(Box<Config>),
+    /// Always encrypts to the same recipients, without rules or manifests.
+    Explicit {
+        identities: Vec<Box<dyn Identity>>,
+        recipients: Vec<RecipientKey>,
+    },
+}
+
+impl SecretStore {
+    /// Uses the keys, recipients and rules of the configuration, like the binary.
+    ///
+    /// The agent is used to decrypt if configured and running.
+    pub fn from_config(config: Config) -> Self {
+        Self {
+            keys: Keys::Config(Box::new(config)),
+        }
+    }
+
+    /// Decrypts with the identities and encrypts to the age or SSH recipients.
+    ///
+    /// The `.mctl.toml` rules are ignored and no `.mctl-manifest` is written, the format comes
+    /// from the extension. The previous versions aren't kept, since there is no cache directory.
+    pub fn new<S>(identities: Vec<Box<dyn Identity>>, recipients: &[S]) -> eyre::Result<Self>
+    where
+        S: AsRef<str>,
+    {
+        let recipients = recipients
+            .iter()
+            .map(|recipient| parse_recipient(recipient.as_ref()))
+            .collect::<eyre::Result<Vec<_>>>()?;
+
+        Ok(Self {
+            keys: Keys::Explicit {
+                identities,
+                recipients,
+            },
+        })
+    }
+
+    /// Decrypts a secret file in memory.
+    pub fn read(&self, path: &Path) -> eyre::Result<Zeroizing<Vec<u8>>> {
+        let mut content = Zeroizing::new(Vec::new());
+
+        self.read_to(path, &mut *content)?;
+
+        Ok(content)
+    }
+
+    /// Decrypts a secret file to a writer.
+    pub fn read_to<W>(&self, path: &Path, dst: &mut W) -> eyre::Result<()>
+    where
+        W: Write,
+    {
+        let mut file = std::fs::File::open(path)
+            .wrap_err_with(|| format!("couldn't open secret file: {}", path.display()))?;
+
+        self.decrypt(&mut file, dst)
+            .wrap_err_with(|| format!("couldn't decrypt {}", path.display()))
+    }
+
+    /// Encrypts the content of a reader to a secret file, replacing it atomically.
+    pub fn write<R>(&self, path: &Path, src: &mut R) -> eyre::Result<()>
+    where
+        R: Read,
+    {
+        let _lock = self.lock(path)?;
+
+        self.write_unlocked(path, src)
+    }
+
+    /// Re-encrypts a secret to its current recipients.
+    pub fn rotate(&self, path: &Path) -> eyre::Result<()> {
+        self.edit_with(path, |_| Ok(()))?;
+
+        Ok(())
+    }
+
+    /// Decrypts a secret, changes it in memory with the callback and encrypts it back.
+    ///
+    /// The secret is locked while the callback runs.
+    pub fn edit_with<F>(&self, path: &Path, edit: F) -> eyre::Result<()>
+    where
+        F: FnOnce(&mut Zeroizing<Vec<u8>>) -> eyre::Result<()>,
+    {
+        let _lock = self.lock(path)?;
+
+        let mut content = self.read(path)?;
+
+        edit(&mut content)?;
+
+        self.write_unlocked(path, &mut content.as_slice())
+    }
+
+    /// Decrypts an age stream.
+    pub fn decrypt<R, W>(&self, src: &mut R, dst: &mut W) -> eyre::Result<()>
+    where
+        R: Read,
+        W: Write,
+    {
+        match &self.keys {
+            Keys::Config(config) => decrypt(config, src, dst),
+            Keys::Explicit { identities, .. } => decrypt_with(identities, src, dst),
+        }
+    }
+
+    /// Encrypts a stream to the default recipients, without the rules of a path.
+    pub fn encrypt<R, W>(&self, src: &mut R, dst: &mut W) -> eyre::Result<()>
+    where
+        R: Read,
+        W: Write,
+    {
+        match &self.keys {
+            Keys::Config(config) => encrypt(&config.secrets.recipients()?, src, dst),
+            Keys::Explicit { recipients, .. } => encrypt(recipients, src, dst),
+        }
+    }
+
+    fn lock(&self, path: &Path) -> eyre::Result<Option<SecretLock>> {
+        match &self.keys {
+            Keys::Config(config) => SecretLock::acquire(config, path).map(Some),
+            Keys::Explicit { .. } => Ok(None),
+        }
+    }
+
+    fn write_unlocked<R>(&self, path: &Path, src: &mut R) -> eyre::Result<()>
+    where
+        R: Read,
+    {
+        debug!(path = %path.display(), "writing secret");
+
+        match &self.keys {
+            Keys::Config(config) => {
+                history::save(config, path).wrap_err("couldn't save the previous version")?;
+
+                let recipients = rules::recipients_for(config, path)?;
+                let encoding = rules::encoding_for(path)?;
+
+                write_atomic(path, |file| encrypt_with(&recipients, encoding, src, file))?;
+
+                Manifest::record(config, path, &recipients)
+            }
+            Keys::Explicit { recipients, .. } => {
+                let encoding = Encoding::from_extension(path);
+
+                write_atomic(path, |file| encrypt_with(recipients, encoding, src, file))
+            }
+        }
+    }
+}
+
+impl std::fmt::Debug for SecretStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let keys = match &self.keys {
+            Keys::Config(_) => "config",
+            Keys::Explicit { .. } => "explicit",
+        };
+
+        f.debug_struct("SecretStore").field("keys", &keys).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use pretty_assertions::assert_eq;
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::manifest::MANIFEST_FILE;
+
+    #[test]
+    fn store_with_explicit_keys() {
+        let tmp = TempDir::new().unwrap();
+
+        let identity = age::x25519::Identity::generate();
+        let recipient = identity.to_public().to_string();
+
+        let store = SecretStore::new(vec![Box::new(identity)], &[recipient]).unwrap();
+
+        let file = tmp.path().join("secret.env.pem");
+
+        store.write(&file, &mut "USER=admin\n".as_bytes()).unwrap();
+        assert_eq!(store.read(&file).unwrap().as_slice(), b"USER=admin\n");
+
+        store
+            .edit_with(&file, |content| {
+                content.extend_from_slice(b"PORT=5432\n");
+
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(
+            store.read(&file).unwrap().as_slice(),
+            b"USER=admin\nPORT=5432\n"
+        );
+
+        let before = fs::read(&file).unwrap();
+        store.rotate(&file).unwrap();
+        assert_ne!(fs::read(&file).unwrap(), before);
+
+        let mut encrypted = Vec::new();
+        store
+            .encrypt(&mut "stream".as_bytes(), &mut encrypted)
+            .unwrap();
+
+        let mut out = Vec::new();
+        store.decrypt(&mut encrypted.as_slice(), &mut out).unwrap();
+        assert_eq!(out, b"stream");
+
+        // A failed edit leaves the secret untouched
+        let res = store.edit_with(&file, |content| {
+            content.clear();
+
+            Err(eyre::eyre!("invalid"))
+        });
+        assert!(res.is_err());
+        assert_eq!(
+            store.read(&file).unwrap().as_slice(),
+            b"USER=admin\nPORT=5432\n"
+        );
+    }
+
+    #[test]
+    fn explicit_keys_ignore_rules_and_manifests() {
+        let tmp = TempDir::new().unwrap();
+
+        fs::write(
+            tmp.path().join(".mctl.toml"),
+            r#"
+                [[rules]]
+                path = "*.pem"
+                recipients = ["invalid"]
+                format = "binary"
+                compress = true
+            "#,
+        )
+        .unwrap();
+
+        let identity = age::x25519::Identity::generate();
+        let recipient = identity.to_public().to_string();
+
+        let store = SecretStore::new(vec![Box::new(identity)], &[recipient]).unwrap();
+
+        let file = tmp.path().join("secret.pem");
+        store.write(&file, &mut "secret".as_bytes()).unwrap();
+
+        assert!(fs::read(&file).unwrap().starts_with(b"-----BEGIN AGE"));
+        assert_eq!(store.read(&file).unwrap().as_slice(), b"secret");
+        assert!(!tmp.path().join(MANIFEST_FILE).exists());
+    }
+
+    #[test]
+    fn store_from_config() {
+        let tmp = TempDir::new().unwrap();
+
+        let store =
+            SecretStore::from_config(Config::mock().with_history_dir(&tmp.path().join("history")));
+
+        let file = tmp.path().join("secret.pem");
+
+        store.write(&file, &mut "one".as_bytes()).unwrap();
+        store.write(&file, &mut "two".as_bytes()).unwrap();
+        assert_eq!(store.read(&file).unwrap().as_slice(), b"two");
+
+        let Keys::Config(config) = &store.keys else {
+            unreachable!();
+        };
+        assert_eq!(history::versions(config, &file).unwrap().len(), 1);
+    }
+}
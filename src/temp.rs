@@ -0,0 +1,220 @@
+//! Location of the plaintext temporary files, in memory when possible.
+
+use std::{
+    fs::DirBuilder,
+    os::{
+        fd::{AsRawFd, OwnedFd},
+        unix::fs::{DirBuilderExt, MetadataExt},
+    },
+    path::{Path, PathBuf},
+};
+
+use color_eyre::Section;
+use eyre::{OptionExt, WrapErr, ensure, eyre};
+use rustix::fs::MemfdFlags;
+use tracing::{debug, warn};
+
+use crate::{
+    config::{Config, TempStrategy},
+    lock::SecretLock,
+};
+
+/// Magic numbers of the RAM-backed file systems, see `statfs(2)`.
+const TMPFS_MAGIC: u64 = 0x0102_1994;
+const RAMFS_MAGIC: u64 = 0x8584_58f6;
+
+const SHM_DIR: &str = "/dev/shm";
+
+/// Where to create a temporary file.
+#[derive(Debug)]
+pub(crate) enum TempLocation {
+    Dir(PathBuf),
+    Memfd,
+}
+
+/// Chooses the location of the temporary files from the configured strategy.
+pub(crate) fn location(config: &Config) -> eyre::Result<TempLocation> {
+    match config.dirs.temp {
+        TempStrategy::Memfd => Ok(TempLocation::Memfd),
+        _ => dir(config).map(TempLocation::Dir),
+    }
+}
+
+/// Chooses the directory of the temporary files, like for the extracted archives.
+///
+/// The memory files can't hold directories, so they fall back to the automatic strategy.
+pub(crate) fn dir(config: &Config) -> eyre::Result<PathBuf> {
+    let dir = match config.dirs.temp {
+        TempStrategy::Cache => return Ok(config.dirs.cache()?.to_path_buf()),
+        TempStrategy::Runtime => runtime_dir()
+            .ok_or_eyre("the runtime directory is not set")
+            .note("set XDG_RUNTIME_DIR or change the dirs.temp option")?,
+        TempStrategy::Shm => {
+            ensure!(Path::new(SHM_DIR).is_dir(), "{SHM_DIR} doesn't exist");
+
+            shm_dir()
+        }
+        TempStrategy::Auto | TempStrategy::Memfd => match runtime_dir() {
+            Some(dir) => dir,
+            None if Path::new(SHM_DIR).is_dir() => shm_dir(),
+            None => {
+                let cache = config.dirs.cache()?;
+
+                warn_disk_backed(cache)?;
+
+                return Ok(cache.to_path_buf());
+            }
+        },
+    };
+
+    create_private_dir(&dir)?;
+    warn_disk_backed(&dir)?;
+
+    debug!(dir = %dir.display(), "temporary files directory");
+
+    Ok(dir)
+}
+
+/// Returns the directories that may contain temporary files, even if they don't exist.
+pub(crate) fn dirs(config: &Config) -> eyre::Result<Vec<PathBuf>> {
+    let mut dirs = vec![config.dirs.cache()?.to_path_buf()];
+
+    dirs.extend(runtime_dir());
+    dirs.push(shm_dir());
+
+    Ok(dirs)
+}
+
+/// Creates an anonymous memory file, returning it with its path in this process.
+///
+/// The file is closed on exec, see [`inheritable`] to pass it to the editor.
+pub(crate) fn memfd(name: &str) -> eyre::Result<(OwnedFd, PathBuf)> {
+    let fd = rustix::fs::memfd_create(name, MemfdFlags::CLOEXEC)
+        .wrap_err("couldn't create memory file")?;
+
+    let path = fd_path(&fd);
+
+    debug!(path = %path.display(), "created memory file");
+
+    Ok((fd, path))
+}
+
+/// Duplicates a memory file so it's inherited by the next child process, returning the
+/// duplicate with its path in the child.
+///
+/// The duplicate should be closed as soon as the child is spawned.
+pub(crate) fn inheritable(fd: &OwnedFd) -> eyre::Result<(OwnedFd, PathBuf)> {
+    let dup = rustix::io::dup(fd).wrap_err("couldn't duplicate memory file")?;
+
+    let path = fd_path(&dup);
+
+    Ok((dup, path))
+}
+
+fn fd_path(fd: &OwnedFd) -> PathBuf {
+    PathBuf::from(format!("/proc/self/fd/{}", fd.as_raw_fd()))
+}
+
+/// Marks a temporary file or directory as used by an edit until the lock is dropped, so it isn't
+/// removed as orphaned.
+pub(crate) fn lock_in_use(path: &Path) -> eyre::Result<SecretLock> {
+    SecretLock::try_lock(in_use_lock_path(path))?
+        .ok_or_else(|| eyre!("the temporary file is in use: {}", path.display()))
+}
+
+/// Hidden lock file next to a temporary file, held while it's in use.
+pub(crate) fn in_use_lock_path(path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+
+    path.with_file_name(format!(".{name}.lock"))
+}
+
+/// Directory of mctl in the runtime directory of the user.
+pub(crate) fn runtime_dir() -> Option<PathBuf> {
+    dirs::runtime_dir().map(|dir| dir.join("mctl"))
+}
+
+/// Directory of the user in the shared `/dev/shm`.
+fn shm_dir() -> PathBuf {
+    let uid = rustix::process::getuid().as_raw();
+
+    Path::new(SHM_DIR).join(format!("mctl-{uid}"))
+}
+
+/// Creates a directory accessible only by the user, checking an existing one wasn't created by
+/// someone else.
+pub(crate) fn create_private_dir(dir: &Path) -> eyre::Result<()> {
+    DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(dir)
+        .wrap_err_with(|| format!("couldn't create temporary directory: {}", dir.display()))?;
+
+    let md = dir.metadata()?;
+
+    ensure!(
+        md.uid() == rustix::process::getuid().as_raw() && md.mode() & 0o077 == 0,
+        "the temporary directory must be owned and accessible only by the user: {}",
+        dir.display()
+    );
+
+    Ok(())
+}
+
+fn warn_disk_backed(dir: &Path) -> eyre::Result<()> {
+    if !is_ram_backed(dir)? {
+        warn!(
+            dir = %dir.display(),
+            "the decrypted secrets are stored on a disk-backed file system"
+        );
+    }
+
+    Ok(())
+}
+
+fn is_ram_backed(dir: &Path) -> eyre::Result<bool> {
+    let stat = rustix::fs::statfs(dir)
+        .wrap_err_with(|| format!("couldn't get the file system of {}", dir.display()))?;
+
+    // The type of the field depends on the architecture
+    let f_type = stat.f_type as u64;
+
+    Ok(f_type == TMPFS_MAGIC || f_type == RAMFS_MAGIC)
+}
+
+#[cfg(test)]
+mod tests {
+    use rustix::io::FdFlags;
+
+    use super::*;
+
+    #[test]
+    fn detect_ram_backed_dirs() {
+        if Path::new(SHM_DIR).is_dir() {
+            assert!(is_ram_backed(Path::new(SHM_DIR)).unwrap());
+        }
+
+        assert!(is_ram_backed(Path::new("/nonexistent")).is_err());
+    }
+
+    #[test]
+    fn memory_file_closed_on_exec() {
+        let (fd, _) = memfd("secret").unwrap();
+        assert!(
+            rustix::io::fcntl_getfd(&fd)
+                .unwrap()
+                .contains(FdFlags::CLOEXEC)
+        );
+
+        let (dup, path) = inheritable(&fd).unwrap();
+        assert!(
+            !rustix::io::fcntl_getfd(&dup)
+                .unwrap()
+                .contains(FdFlags::CLOEXEC)
+        );
+        assert_eq!(
+            path,
+            Path::new(&format!("/proc/self/fd/{}", dup.as_raw_fd()))
+        );
+    }
+}
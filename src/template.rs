@@ -0,0 +1,191 @@
+//! Templates with references to secrets, like `{{ secret "db.toml.pem" "password" }}`.
+//!
+//! A literal `{{` is written `{{{{`.
+
+use std::path::PathBuf;
+
+use eyre::{bail, ensure, eyre};
+
+const OPEN: &str = "{{";
+const CLOSE: &str = "}}";
+
+/// Escaped opening, rendered as a literal one.
+const ESCAPED_OPEN: &str = "{{{{";
+
+/// Part of a parsed template.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Part<'a> {
+    Text(&'a str),
+    Secret(Reference),
+}
+
+/// Reference to a secret, or a field of a structured secret.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Reference {
+    /// Line of the placeholder in the template, starting from 1
+    pub(crate) line: usize,
+    /// Path to the secret, relative to the template
+    pub(crate) file: PathBuf,
+    pub(crate) field: Option<String>,
+}
+
+impl std::fmt::Display for Reference {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}: secret {:?}", self.line, self.file.display())?;
+
+        if let Some(field) = &self.field {
+            write!(f, " {field:?}")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Splits the template in text and secret references.
+pub(crate) fn parse(template: &str) -> eyre::Result<Vec<Part<'_>>> {
+    let mut parts = Vec::new();
+    let mut rest = template;
+
+    while let Some(start) = rest.find(OPEN) {
+        let offset = template.len() - rest.len() + start;
+        let line = template[..offset].matches('\n').count() + 1;
+
+        if start > 0 {
+            parts.push(Part::Text(&rest[..start]));
+        }
+
+        if rest[start..].starts_with(ESCAPED_OPEN) {
+            parts.push(Part::Text(&rest[start..start + OPEN.len()]));
+
+            rest = &rest[start + ESCAPED_OPEN.len()..];
+
+            continue;
+        }
+
+        let inner = &rest[start + OPEN.len()..];
+        let end = inner
+            .find(CLOSE)
+            .ok_or_else(|| eyre!("unclosed placeholder on line {line}"))?;
+
+        let reference = parse_placeholder(&inner[..end], line)
+            .map_err(|err| err.wrap_err(format!("invalid placeholder on line {line}")))?;
+
+        parts.push(Part::Secret(reference));
+
+        rest = &inner[end + CLOSE.len()..];
+    }
+
+    if !rest.is_empty() {
+        parts.push(Part::Text(rest));
+    }
+
+    Ok(parts)
+}
+
+fn parse_placeholder(placeholder: &str, line: usize) -> eyre::Result<Reference> {
+    let placeholder = placeholder.trim();
+
+    let Some(mut args) = placeholder.strip_prefix("secret") else {
+        bail!("unknown function: {placeholder}");
+    };
+
+    let mut strings = Vec::new();
+    loop {
+        args = args.trim_start();
+
+        if args.is_empty() {
+            break;
+        }
+
+        let (string, rest) = parse_string(args)?;
+
+        strings.push(string);
+        args = rest;
+    }
+
+    let mut strings = strings.into_iter();
+
+    let file = strings.next().ok_or_else(|| eyre!("missing secret path"))?;
+    let field = strings.next();
+
+    ensure!(
+        strings.next().is_none(),
+        "expected the secret path and an optional field"
+    );
+
+    Ok(Reference {
+        line,
+        file: PathBuf::from(file),
+        field,
+    })
+}
+
+/// Parses a double quoted string, returning it with the rest of the input.
+fn parse_string(input: &str) -> eyre::Result<(String, &str)> {
+    let Some(input) = input.strip_prefix('"') else {
+        bail!("expected a quoted string: {input}");
+    };
+
+    let mut string = String::new();
+    let mut chars = input.char_indices();
+    while let Some((idx, c)) = chars.next() {
+        match c {
+            '"' => return Ok((string, &input[idx + 1..])),
+            '\\' => {
+                let (_, c) = chars.next().ok_or_else(|| eyre!("unterminated string"))?;
+
+                string.push(c);
+            }
+            c => string.push(c),
+        }
+    }
+
+    bail!("unterminated string")
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn parse_secret_references() {
+        let template = "user = admin\npassword = {{ secret \"db.toml.pem\" \"password\" }}\ntoken = {{secret \"dir/token.pem\"}}";
+
+        let parts = parse(template).unwrap();
+
+        assert_eq!(
+            parts,
+            [
+                Part::Text("user = admin\npassword = "),
+                Part::Secret(Reference {
+                    line: 2,
+                    file: PathBuf::from("db.toml.pem"),
+                    field: Some("password".to_string()),
+                }),
+                Part::Text("\ntoken = "),
+                Part::Secret(Reference {
+                    line: 3,
+                    file: PathBuf::from("dir/token.pem"),
+                    field: None,
+                }),
+            ]
+        );
+
+        assert_eq!(
+            parse("{{{{ not a secret }} {{{{{{{{").unwrap(),
+            [
+                Part::Text("{{"),
+                Part::Text(" not a secret }} "),
+                Part::Text("{{"),
+                Part::Text("{{"),
+            ]
+        );
+
+        assert!(parse("{{ secret \"a.pem\" ").is_err());
+        assert!(parse("{{ env \"HOME\" }}").is_err());
+        assert!(parse("{{ secret }}").is_err());
+        assert!(parse("{{ secret \"a.pem\" \"b\" \"c\" }}").is_err());
+    }
+}
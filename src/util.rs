@@ -1,10 +1,177 @@
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, File, Permissions};
+use std::io::{self, Read};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt, PermissionsExt, fchown};
+use std::path::Path;
+
+use eyre::{WrapErr, eyre};
 use rand::RngExt;
-use rand::distr::Alphanumeric;
+use rand::distr::slice::Choose;
+use tracing::{debug, error, warn};
+use zeroize::Zeroizing;
+
+/// Letters and digits, the default characters of the passwords.
+pub(crate) const ALPHA_NUM: &str = "ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
 
 pub(crate) fn random_alpha_num() -> String {
-    rand::rng()
-        .sample_iter(Alphanumeric)
-        .take(8)
-        .map(char::from)
-        .collect()
+    let charset = ALPHA_NUM.chars().collect::<Vec<_>>();
+
+    random_string(&charset, 8)
+        .expect("the charset isn't empty")
+        .to_string()
+}
+
+/// Returns a string of the given length with characters chosen uniformly from the charset.
+///
+/// The string is allocated once, so no copy of it is left in memory.
+pub(crate) fn random_string(charset: &[char], len: usize) -> eyre::Result<Zeroizing<String>> {
+    let distr = Choose::new(charset).map_err(|_| eyre!("the charset is empty"))?;
+
+    let max_char_len = charset.iter().map(|c| c.len_utf8()).max().unwrap_or(1);
+
+    let mut string = Zeroizing::new(String::with_capacity(len * max_char_len));
+    for c in rand::rng().sample_iter(distr).take(len) {
+        string.push(*c);
+    }
+
+    Ok(string)
+}
+
+/// Returns the given number of random bytes.
+pub(crate) fn random_bytes(len: usize) -> Zeroizing<Vec<u8>> {
+    let mut bytes = Zeroizing::new(vec![0; len]);
+
+    rand::rng().fill(bytes.as_mut_slice());
+
+    bytes
+}
+
+/// Creates or truncates a file readable only by the owner.
+pub(crate) fn create_private_file(path: &Path) -> eyre::Result<File> {
+    File::options()
+        .create(true)
+        .truncate(true)
+        .write(true)
+        .read(true)
+        .mode(0o600)
+        .open(path)
+        .wrap_err_with(|| format!("couldn't open file: {}", path.display()))
+}
+
+/// Overwrites a file with zeros before removing it, to not leave the plaintext on disk.
+pub(crate) fn remove_securely(path: &Path) -> eyre::Result<()> {
+    let mut file = File::options()
+        .write(true)
+        .open(path)
+        .wrap_err_with(|| format!("couldn't open file: {}", path.display()))?;
+
+    let len = file.metadata()?.len();
+
+    io::copy(&mut io::repeat(0).take(len), &mut file)?;
+    file.sync_all()?;
+
+    fs::remove_file(path).wrap_err_with(|| format!("couldn't remove file: {}", path.display()))
+}
+
+/// Overwrites the files of a directory tree with zeros before removing it.
+pub(crate) fn remove_dir_securely(dir: &Path) -> eyre::Result<()> {
+    // The extracted directories and files may be read-only
+    fs::set_permissions(dir, Permissions::from_mode(0o700))
+        .wrap_err_with(|| format!("couldn't change the mode of {}", dir.display()))?;
+
+    for entry in
+        fs::read_dir(dir).wrap_err_with(|| format!("couldn't read directory: {}", dir.display()))?
+    {
+        let entry = entry?;
+        let path = entry.path();
+        let file_type = entry.file_type()?;
+
+        if file_type.is_dir() {
+            remove_dir_securely(&path)?;
+        } else if file_type.is_file() {
+            fs::set_permissions(&path, Permissions::from_mode(0o600))?;
+
+            remove_securely(&path)?;
+        } else {
+            fs::remove_file(&path)
+                .wrap_err_with(|| format!("couldn't remove file: {}", path.display()))?;
+        }
+    }
+
+    fs::remove_dir(dir).wrap_err_with(|| format!("couldn't remove directory: {}", dir.display()))
+}
+
+/// Writes a file through a sibling temporary file renamed over the original.
+///
+/// The previous content is left untouched if the write fails at any point.
+pub(crate) fn write_atomic<F>(path: &Path, write: F) -> eyre::Result<()>
+where
+    F: FnOnce(&mut File) -> eyre::Result<()>,
+{
+    let dir = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+
+    let mut tmp_name = OsString::from(".");
+    tmp_name.push(path.file_name().unwrap_or(OsStr::new("file")));
+    tmp_name.push(".");
+    tmp_name.push(random_alpha_num());
+    tmp_name.push(".tmp");
+
+    let tmp_path = dir.join(tmp_name);
+
+    debug!(path = %tmp_path.display(), "writing to temporary file");
+
+    let res = File::options()
+        .create_new(true)
+        .write(true)
+        .mode(0o600)
+        .open(&tmp_path)
+        .wrap_err("couldn't create temporary file")
+        .and_then(|mut file| {
+            copy_metadata(path, &file)?;
+
+            write(&mut file)?;
+
+            file.sync_all().wrap_err("couldn't sync temporary file")
+        })
+        .and_then(|()| fs::rename(&tmp_path, path).wrap_err("couldn't replace file"));
+
+    if let Err(err) = res {
+        if let Err(rm_err) = fs::remove_file(&tmp_path)
+            && rm_err.kind() != io::ErrorKind::NotFound
+        {
+            error!(error = %rm_err, "couldn't remove temporary file");
+        }
+
+        return Err(err);
+    }
+
+    File::open(dir)
+        .and_then(|dir| dir.sync_all())
+        .wrap_err("couldn't sync directory")?;
+
+    Ok(())
+}
+
+/// Copies the mode and ownership of the existing file to the new one.
+fn copy_metadata(path: &Path, file: &File) -> eyre::Result<()> {
+    let md = match path.metadata() {
+        Ok(md) => md,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err).wrap_err("couldn't read file metadata"),
+    };
+
+    file.set_permissions(md.permissions())
+        .wrap_err("couldn't set file permissions")?;
+
+    let current = file.metadata()?;
+    if (current.uid(), current.gid()) != (md.uid(), md.gid())
+        && let Err(err) = fchown(file, Some(md.uid()), Some(md.gid()))
+    {
+        warn!(error = %err, "couldn't preserve the ownership of the file");
+    }
+
+    Ok(())
 }